@@ -0,0 +1,53 @@
+//! Compares reading values through `get_at` (seek + `BufReader`) with the
+//! zero-copy `get_mapped` path.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use libactionkv::ActionKV;
+
+const KEYS: u32 = 10_000;
+
+fn populate(path: &std::path::Path, value_len: usize) -> ActionKV {
+    let _ = std::fs::remove_file(path);
+    let mut store = ActionKV::open(path).unwrap();
+    let value = vec![0xAB; value_len];
+    for i in 0..KEYS {
+        store.insert(&i.to_le_bytes(), &value).unwrap();
+    }
+    store
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+
+    for value_len in [64usize, 4096, 65536] {
+        let path = std::env::temp_dir().join(format!("akv_bench_{}.akv", value_len));
+        let mut store = populate(&path, value_len);
+        store.map().unwrap();
+
+        let positions: Vec<u64> = (0..KEYS).map(|i| store.index[&i.to_le_bytes()[..]]).collect();
+
+        group.bench_with_input(BenchmarkId::new("get_at", value_len), &positions, |b, positions| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 7919) % positions.len(); // hop around the file rather than reading sequentially
+                black_box(store.get_at(positions[i]).unwrap().value.len())
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("get_mapped", value_len), &(), |b, _| {
+            let mut i = 0u32;
+            b.iter(|| {
+                i = (i + 7919) % KEYS;
+                black_box(store.get_mapped(&i.to_le_bytes()).unwrap().unwrap().len())
+            })
+        });
+
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_get);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::io::Write;
+
+use libactionkv::ActionKV;
+use libfuzzer_sys::fuzz_target;
+
+// the input is used as a whole store file, header included
+fuzz_target!(|data: &[u8]| {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(data).unwrap();
+
+    let mut store = match ActionKV::open(file.path()) {
+        Ok(store) => store,
+        Err(_) => return,
+    };
+    if store.load().is_err() {
+        return;
+    }
+
+    // whatever loaded must be readable, or fail cleanly
+    let keys: Vec<Vec<u8>> = store.index.keys().cloned().collect();
+    for key in keys {
+        let _ = store.get(&key);
+    }
+    let _ = store.stats();
+    let _ = store.list_tables();
+});
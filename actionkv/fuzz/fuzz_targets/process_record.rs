@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libactionkv::ActionKV;
+use libfuzzer_sys::fuzz_target;
+
+// any bytes must decode to a record or an error, without panicking or allocating
+// more than the input could hold
+fuzz_target!(|data: &[u8]| {
+    let mut f = Cursor::new(data);
+    while let Ok(kv) = ActionKV::process_record(&mut f) {
+        assert!(kv.key.len() + kv.value.len() <= data.len());
+    }
+});
@@ -1,5 +1,6 @@
-use libactionkv::ActionKV;
+use libactionkv::{ActionKV, KvError};
 use std::collections::HashMap;
+use std::io;
 
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
@@ -22,60 +23,88 @@ Usage:
 type ByteStr = [u8]; // 8-bit unsigned integer type.
 type ByteString = Vec<u8>;
 
-fn store_index_on_disk(a: &mut ActionKV, index_key: &ByteStr) {
-    a.index.remove(index_key);
-    
-    let index_as_bytes = bincode::serialize(&a.index).unwrap();
-    
-    a.index = HashMap::new();
-    a.insert(index_key, &index_as_bytes).unwrap();
-}
+// the index is kept in a bookkeeping table of its own, so it can't collide with a user's key
+const INDEX_TABLE: &str = "+akv_disk";
+const INDEX_KEY: &ByteStr = b"index";
 
-fn main() {
-    const INDEX_KEY: &ByteStr = b"+index";
+// files from before format version 2 have no tables, so their index stays under this key
+const LEGACY_INDEX_KEY: &ByteStr = b"+index";
 
-    let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_ref(); 
-    let maybe_value = args.get(4);
+fn store_index_on_disk(a: &mut ActionKV) -> Result<(), KvError> {
+    if !a.has_tables() {
+        a.index.remove(LEGACY_INDEX_KEY); // the index doesn't point at itself
+        let index_as_bytes = bincode::serialize(&a.index).map_err(invalid_index)?;
+        return a.insert(LEGACY_INDEX_KEY, &index_as_bytes);
+    }
 
-    let path = std::path::Path::new(&fname);
-    let mut a = ActionKV::open(path).expect("unable to open file");
+    let index_as_bytes = bincode::serialize(&a.index).map_err(invalid_index)?;
 
-    a.load().expect("unable to load data");
+    a.open_table(INDEX_TABLE)?.insert(INDEX_KEY, &index_as_bytes)
+}
 
-    match action {
-        "get" => {
-            let index_as_bytes = a.get(&INDEX_KEY).unwrap().unwrap();
+// the on-disk index is stored like any other value, so a bad one is reported as bad data
+fn invalid_index(err: bincode::Error) -> KvError {
+    KvError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+}
 
-            let index_decoded = bincode::deserialize(&index_as_bytes);
+fn run(fname: &str, action: &str, key: &ByteStr, maybe_value: Option<&String>) -> Result<(), KvError> {
+    let path = std::path::Path::new(fname);
+    let mut a = ActionKV::open(path)?;
 
-            let index: HashMap<ByteString, u64> = index_decoded.unwrap();
+    a.load()?;
+
+    match action {
+        "get" => {
+            let stored_index = if !a.has_tables() {
+                a.get(LEGACY_INDEX_KEY)?
+            } else if a.has_table(INDEX_TABLE) {
+                a.open_table(INDEX_TABLE)?.get(INDEX_KEY)?
+            } else {
+                None // nothing has been inserted yet
+            };
+
+            let index: HashMap<ByteString, u64> = match stored_index {
+                None => HashMap::new(),
+                Some(index_as_bytes) => bincode::deserialize(&index_as_bytes).map_err(invalid_index)?,
+            };
 
             match index.get(key) {
                 None => eprintln!("{:?} not found", key),
                 Some(&i) => {
-                    let kv = a.get_at(i).unwrap();
+                    let kv = a.get_at(i)?;
                     println!("{:?}", kv.value);
                 }
             }
         }
 
-        "delete" => a.delete(key).unwrap(),
+        "delete" => a.delete(key)?,
 
         "insert" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
-            a.insert(key, value).unwrap();
-            store_index_on_disk(&mut a, INDEX_KEY); // index must be updated when data changes
+            let value = maybe_value.expect(USAGE).as_ref();
+            a.insert(key, value)?;
+            store_index_on_disk(&mut a)?; // index must be updated when data changes
         }
 
         "update" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
-            a.update(key, value).unwrap();
-            store_index_on_disk(&mut a, INDEX_KEY);
+            let value = maybe_value.expect(USAGE).as_ref();
+            a.update(key, value)?;
+            store_index_on_disk(&mut a)?;
         }
 
         _ => eprintln!("{}", &USAGE),
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE);
+    let key = args.get(3).expect(USAGE).as_ref(); 
+
+    if let Err(err) = run(fname, action, key, args.get(4)) {
+        eprintln!("{}: {}", fname, err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use libactionkv::{ActionKV, Encoding, KvError, SharedActionKV};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_http.exe FILE [--listen ADDR]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_http FILE [--listen ADDR]
+";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+const WORKERS: usize = 4; // requests are served concurrently, store access is serialized by the lock
+const MAX_VALUE_LEN: u64 = 16 * 1024 * 1024; // PUT bodies are held in memory until they're written
+
+type ByteStr = [u8];
+type ByteString = Vec<u8>;
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Key counts served by `/stats`, kept up to date by the handlers so that serving them
+/// doesn't read the whole file with the store locked. Only this process can write to
+/// the store while it holds the file, so nothing else can make them stale.
+#[derive(Debug)]
+struct Counters {
+    live_keys: AtomicU64,
+    tombstones: AtomicU64,
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header names and values are ASCII")
+}
+
+fn request_header<'r>(request: &'r Request, name: &'static str) -> Option<&'r str> {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+}
+
+fn empty(status: u16) -> HttpResponse {
+    Response::from_data(Vec::new()).with_status_code(status)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> HttpResponse {
+    Response::from_data(body.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    json_response(status, json!({ "error": message }))
+}
+
+/// Decodes `%XX` escapes in a URL component. In query strings `+` also stands for a space.
+fn percent_decode(text: &str, in_query: bool) -> Option<ByteString> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // from_str_radix alone would accept a sign, as in "%+1"
+                let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            }
+            b'+' if in_query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    Some(decoded)
+}
+
+fn query_param(query: &str, name: &str) -> Option<ByteString> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value, true))
+}
+
+// the record checksum covers the key and the value, so a new value all but always gets a new one.
+// Rewriting the same value keeps it, which is right for a strong validator: the bytes are the same
+fn etag(checksum: u32) -> String {
+    format!("\"{:08x}\"", checksum)
+}
+
+/// True if an `If-Match` or `If-None-Match` header value names `current`. `*` names any
+/// existing value; nothing matches a missing key.
+fn etag_matches(condition: &str, current: Option<&str>) -> bool {
+    match current {
+        None => false,
+        Some(current) => condition
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == current || tag.strip_prefix("W/") == Some(current)),
+    }
+}
+
+/// Returns the live value of `key` with its ETag, or `None` if the key is absent or deleted.
+fn current(store: &mut ActionKV, key: &ByteStr) -> Result<Option<(ByteString, String)>, KvError> {
+    let value = match store.get(key)? {
+        Some(value) if !value.is_empty() => value,
+        _ => return Ok(None),
+    };
+    let checksum = store.checksum(key)?.expect("key was just read");
+
+    Ok(Some((value, etag(checksum))))
+}
+
+/// Checks the `If-Match` and `If-None-Match` headers of a write against the current ETag.
+fn preconditions_hold(request: &Request, current: Option<&str>) -> bool {
+    if let Some(condition) = request_header(request, "If-Match") {
+        if !etag_matches(condition, current) {
+            return false;
+        }
+    }
+    if let Some(condition) = request_header(request, "If-None-Match") {
+        if etag_matches(condition, current) {
+            return false;
+        }
+    }
+    true
+}
+
+fn get_key(store: &SharedActionKV, request: &Request, key: &ByteStr) -> Result<HttpResponse, KvError> {
+    let (value, etag) = match current(&mut store.lock(), key)? {
+        None => return Ok(error(404, "no such key")),
+        Some(found) => found,
+    };
+
+    if request_header(request, "If-None-Match").is_some_and(|condition| etag_matches(condition, Some(&etag))) {
+        return Ok(empty(304).with_header(header("ETag", &etag)));
+    }
+
+    Ok(Response::from_data(value)
+        .with_header(header("Content-Type", "application/octet-stream"))
+        .with_header(header("ETag", &etag)))
+}
+
+fn put_key(store: &SharedActionKV, counters: &Counters, request: &mut Request, key: &ByteStr) -> Result<HttpResponse, KvError> {
+    let too_large = || error(413, &format!("values can't be larger than {} bytes", MAX_VALUE_LEN));
+    if request.body_length().is_some_and(|len| len as u64 > MAX_VALUE_LEN) {
+        return Ok(too_large());
+    }
+
+    let mut value = Vec::new();
+    request.as_reader().take(MAX_VALUE_LEN + 1).read_to_end(&mut value)?; // read before locking, clients can be slow
+    if value.len() as u64 > MAX_VALUE_LEN {
+        return Ok(too_large()); // a chunked body has no Content-Length to check up front
+    }
+    if value.is_empty() {
+        return Ok(error(400, "empty values can't be stored, use DELETE instead"));
+    }
+
+    let mut store = store.lock();
+    let existing = current(&mut store, key)?.map(|(_, etag)| etag);
+    if !preconditions_hold(request, existing.as_deref()) {
+        return Ok(error(412, "precondition failed"));
+    }
+    let deleted = existing.is_none() && store.index.contains_key(key);
+
+    store.insert(key, &value)?;
+    let etag = etag(store.checksum(key)?.expect("key was just written"));
+    let status = if existing.is_some() { 204 } else { 201 };
+
+    if existing.is_none() {
+        counters.live_keys.fetch_add(1, Ordering::Relaxed);
+    }
+    if deleted {
+        counters.tombstones.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    Ok(empty(status).with_header(header("ETag", &etag)))
+}
+
+fn delete_key(store: &SharedActionKV, counters: &Counters, request: &Request, key: &ByteStr) -> Result<HttpResponse, KvError> {
+    let mut store = store.lock();
+    let existing = match current(&mut store, key)? {
+        None => return Ok(error(404, "no such key")),
+        Some((_, etag)) => etag,
+    };
+    if !preconditions_hold(request, Some(&existing)) {
+        return Ok(error(412, "precondition failed"));
+    }
+
+    store.delete(key)?;
+    counters.live_keys.fetch_sub(1, Ordering::Relaxed);
+    counters.tombstones.fetch_add(1, Ordering::Relaxed);
+
+    Ok(empty(204))
+}
+
+fn list_keys(store: &SharedActionKV, query: &str) -> Result<HttpResponse, KvError> {
+    let prefix = query_param(query, "prefix").unwrap_or_default();
+    let encoding = match query_param(query, "encoding") {
+        None => Encoding::Utf8Lossy,
+        Some(name) => match String::from_utf8_lossy(&name).parse::<Encoding>() {
+            Ok(encoding) => encoding,
+            Err(err) => return Ok(error(400, &err)),
+        },
+    };
+
+    let keys: Vec<String> = store.lock().keys(&prefix)?.iter().map(|key| encoding.encode(key)).collect();
+
+    Ok(json_response(200, json!({ "keys": keys })))
+}
+
+fn stats(store: &SharedActionKV, counters: &Counters) -> HttpResponse {
+    let store = store.lock();
+    json_response(
+        200,
+        json!({
+            "live_keys": counters.live_keys.load(Ordering::Relaxed),
+            "tombstones": counters.tombstones.load(Ordering::Relaxed),
+            "dead_bytes": store.dead_bytes(),
+            "file_size": store.file_len(),
+        }),
+    )
+}
+
+fn handle(store: &SharedActionKV, counters: &Counters, request: &mut Request) -> Result<HttpResponse, KvError> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let method = request.method().clone();
+
+    if let Some(encoded_key) = path.strip_prefix("/kv/") {
+        let key = match percent_decode(encoded_key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return Ok(error(400, "invalid key")),
+        };
+        return match method {
+            Method::Get => get_key(store, request, &key),
+            Method::Put => put_key(store, counters, request, &key),
+            Method::Delete => delete_key(store, counters, request, &key),
+            _ => Ok(error(405, "method not allowed")),
+        };
+    }
+
+    match (method, path) {
+        (Method::Get, "/kv") => list_keys(store, query),
+        (Method::Get, "/stats") => Ok(stats(store, counters)),
+        (Method::Get, "/health") => Ok(json_response(200, json!({ "status": "ok" }))),
+        (_, "/kv") | (_, "/stats") | (_, "/health") => Ok(error(405, "method not allowed")),
+        _ => Ok(error(404, "not found")),
+    }
+}
+
+fn serve(server: &Server, store: &SharedActionKV, counters: &Counters) {
+    for mut request in server.incoming_requests() {
+        let response = handle(store, counters, &mut request).unwrap_or_else(|err| {
+            eprintln!("{} {}: {}", request.method(), request.url(), err);
+            error(500, &err.to_string())
+        });
+
+        if let Err(err) = request.respond(response) {
+            eprintln!("failed to send response: {}", err);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).expect(USAGE);
+    let listen = match args.get(2).map(String::as_str) {
+        Some("--listen") => args.get(3).expect(USAGE).as_str(),
+        Some(_) => panic!("{}", USAGE),
+        None => DEFAULT_LISTEN,
+    };
+
+    // the counters are taken from one full read of the file, before anything is served
+    let store = ActionKV::open(std::path::Path::new(fname)).and_then(|mut store| {
+        store.load()?;
+        let stats = store.stats()?;
+        let counters = Counters { live_keys: stats.live_keys.into(), tombstones: stats.tombstones.into() };
+        Ok((store, Arc::new(counters)))
+    });
+    let (store, counters) = match store {
+        Ok((store, counters)) => (SharedActionKV::new(store), counters),
+        Err(err) => {
+            eprintln!("{}: {}", fname, err);
+            std::process::exit(1);
+        }
+    };
+
+    let server = match Server::http(listen) {
+        Ok(server) => Arc::new(server),
+        Err(err) => {
+            eprintln!("{}: {}", listen, err);
+            std::process::exit(1);
+        }
+    };
+    println!("listening on http://{}", server.server_addr());
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let (server, store, counters) = (Arc::clone(&server), store.clone(), Arc::clone(&counters));
+            thread::spawn(move || serve(&server, &store, &counters))
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().expect("worker thread panicked");
+    }
+}
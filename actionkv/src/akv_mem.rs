@@ -1,4 +1,8 @@
-use libactionkv::ActionKV;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::str::FromStr;
+
+use libactionkv::{ActionKV, DumpFormat, Encoding, KvError, SizeHistogram, StoreStats};
 
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
@@ -7,6 +11,10 @@ Usage:
     akv_mem.exe FILE delete KEY
     akv_mem.exe FILE insert KEY VALUE
     akv_mem.exe FILE update KEY VALUE
+    akv_mem.exe FILE stats [--json]
+    akv_mem.exe FILE compact
+    akv_mem.exe FILE export [--format jsonl|csv|sql] [--encoding hex|base64|utf8-lossy] [OUTPUT]
+    akv_mem.exe FILE import [--format jsonl|csv|sql] [--encoding hex|base64|utf8-lossy] [INPUT]
 ";
 
 #[cfg(not(target_os = "windows"))]
@@ -16,37 +24,143 @@ Usage:
     akv_mem FILE delete KEY
     akv_mem FILE insert KEY VALUE
     akv_mem FILE update KEY VALUE
+    akv_mem FILE stats [--json]
+    akv_mem FILE compact
+    akv_mem FILE export [--format jsonl|csv|sql] [--encoding hex|base64|utf8-lossy] [OUTPUT]
+    akv_mem FILE import [--format jsonl|csv|sql] [--encoding hex|base64|utf8-lossy] [INPUT]
 ";
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_ref();
-    let maybe_value = args.get(4);
+fn print_histogram(name: &str, histogram: &SizeHistogram) {
+    println!("{}:", name);
+    for bucket in histogram.buckets.iter().filter(|b| b.count > 0) {
+        println!("    <= {:>10} bytes: {}", bucket.upper_bound, bucket.count);
+    }
+}
+
+fn print_stats(stats: &StoreStats) {
+    println!("file size:         {} bytes", stats.file_size);
+    println!("total records:     {}", stats.total_records);
+    println!("live keys:         {}", stats.live_keys);
+    println!("tombstones:        {}", stats.tombstones);
+    println!("internal records:  {}", stats.internal_records);
+    println!("dead bytes:        {}", stats.dead_bytes);
+    println!("reclaimable bytes: {}", stats.reclaimable_bytes);
+    print_histogram("key sizes", &stats.key_sizes);
+    print_histogram("value sizes", &stats.value_sizes);
+}
+
+/// Prints `message` and the usage, and exits with an error.
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}{}", message, USAGE);
+    std::process::exit(1);
+}
+
+/// Parses the value given to the option `name`, exiting with a usage error if it's missing or invalid.
+fn option_value<T: FromStr<Err = String>>(name: &str, value: Option<&String>) -> T {
+    match value.map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        Some(Err(err)) => usage_error(&err),
+        None => usage_error(&format!("{} needs a value", name)),
+    }
+}
 
-    let path = std::path::Path::new(&fname);
-    let mut store = ActionKV::open(path).expect("unable to open file");
-    store.load().expect("unable to load data");
+/// Splits the arguments of `export` and `import` into the format, the encoding and the
+/// optional file name. The format defaults to JSON Lines and the encoding to hex.
+fn dump_options(args: &[String]) -> (DumpFormat, Encoding, Option<&String>) {
+    let mut format = DumpFormat::JsonLines;
+    let mut encoding = Encoding::Hex;
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = option_value("--format", args.next()),
+            "--encoding" => encoding = option_value("--encoding", args.next()),
+            _ => path = Some(arg),
+        }
+    }
+
+    (format, encoding, path)
+}
+
+fn run(fname: &str, action: &str, args: &[String]) -> Result<(), KvError> {
+    let maybe_key = args.first();
+    let maybe_value = args.get(1);
+    let path = std::path::Path::new(fname);
+    let mut store = ActionKV::open(path)?;
+    store.load()?;
 
     match action {
-        "get" => match store.get(key).unwrap() {
-            None => eprintln!("{:?} not found", key),
-            Some(value) => println!("{:?}", value),
-        },
+        "get" => {
+            let key = maybe_key.expect(USAGE).as_ref();
+            match store.get(key)? {
+                None => eprintln!("{:?} not found", key),
+                Some(value) => println!("{:?}", value),
+            }
+        }
 
-        "delete" => store.delete(key).unwrap(),
+        "delete" => {
+            let key = maybe_key.expect(USAGE).as_ref();
+            store.delete(key)?
+        }
 
         "insert" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
-            store.insert(key, value).unwrap();
+            let key = maybe_key.expect(USAGE).as_ref();
+            let value = maybe_value.expect(USAGE).as_ref();
+            store.insert(key, value)?;
         },
 
         "update" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
-            store.update(key, value).unwrap();
+            let key = maybe_key.expect(USAGE).as_ref();
+            let value = maybe_value.expect(USAGE).as_ref();
+            store.update(key, value)?;
+        }
+
+        "stats" => {
+            let stats = store.stats()?;
+            match maybe_key.map(String::as_str) {
+                Some("--json") => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+                None => print_stats(&stats),
+                Some(_) => eprintln!("{}", USAGE),
+            }
+        }
+
+        "compact" => {
+            let reclaimed = store.compact()?;
+            eprintln!("reclaimed {} bytes", reclaimed);
+        }
+
+        "export" => {
+            let (format, encoding, output) = dump_options(args);
+            let written = match output {
+                Some(output) => store.export(File::create(output)?, format, encoding)?,
+                None => store.export(io::stdout().lock(), format, encoding)?,
+            };
+            eprintln!("exported {} keys", written);
         }
 
-        _ => eprintln!("{}", &USAGE),
+        "import" => {
+            let (format, encoding, input) = dump_options(args);
+            let imported = match input {
+                Some(input) => store.import(BufReader::new(File::open(input)?), format, encoding)?,
+                None => store.import(io::stdin().lock(), format, encoding)?,
+            };
+            eprintln!("imported {} keys", imported);
+        }
+
+        _ => eprintln!("{}", USAGE),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let fname = args.get(1).expect(USAGE);
+    let action = args.get(2).expect(USAGE);
+
+    if let Err(err) = run(fname, action, &args[3..]) {
+        eprintln!("{}: {}", fname, err);
+        std::process::exit(1);
     }
-}
\ No newline at end of file
+}
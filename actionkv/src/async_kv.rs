@@ -0,0 +1,114 @@
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::{ActionKV, KvError};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+type Job = Box<dyn FnOnce(&mut ActionKV) + Send>;
+
+/// An async handle to an `ActionKV`, for use from tokio tasks.
+///
+/// The store lives on a dedicated I/O thread that runs operations one at a time, in the
+/// order they were requested, so the blocking file I/O never stalls the runtime. Handles
+/// are cheap to clone; the thread exits and closes the store once every clone is dropped.
+///
+/// Writes are cancellation-safe: an operation is handed to the I/O thread the first time
+/// its future is polled and then runs to completion even if the future is dropped, so a
+/// cancelled write is either applied whole or not at all. Later operations from the same
+/// task always see it.
+#[derive(Debug, Clone)]
+pub struct AsyncActionKV {
+    jobs: mpsc::Sender<Job>,
+}
+
+fn io_thread_gone() -> KvError {
+    KvError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "the ActionKV I/O thread has stopped"))
+}
+
+impl AsyncActionKV {
+    /// Moves `store` onto a new I/O thread.
+    pub fn new(store: ActionKV) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name("actionkv-io".to_string())
+            .spawn(move || {
+                let mut store = store;
+                for job in queue {
+                    job(&mut store);
+                }
+            })
+            .expect("failed to spawn the ActionKV I/O thread");
+
+        AsyncActionKV { jobs }
+    }
+
+    /// Opens and loads the store at `path` without blocking the runtime, like
+    /// `ActionKV::open` followed by `ActionKV::load`.
+    pub async fn open(path: &Path) -> Result<Self, KvError> {
+        let path = path.to_path_buf();
+        let (reply, opened) = oneshot::channel();
+
+        thread::spawn(move || {
+            let store = ActionKV::open(&path).and_then(|mut store| {
+                store.load()?;
+                Ok(store)
+            });
+            let _ = reply.send(store); // the caller may have gone away
+        });
+
+        let store = opened.await.map_err(|_| io_thread_gone())??;
+        Ok(AsyncActionKV::new(store))
+    }
+
+    /// Runs `f` with exclusive access to the store on the I/O thread and returns its result.
+    /// Every other method is built on this one; `f` should not block on anything but the store.
+    pub async fn with_store<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ActionKV) -> Result<T, KvError> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |store| {
+            let _ = reply.send(f(store)); // nobody is waiting if the future was dropped
+        });
+
+        self.jobs.send(job).map_err(|_| io_thread_gone())?;
+        result.await.map_err(|_| io_thread_gone())?
+    }
+
+    /// Retrieves the value stored under `key`, like `ActionKV::get`.
+    pub async fn get(&self, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        let key = key.to_vec();
+        self.with_store(move |store| store.get(&key)).await
+    }
+
+    /// Inserts a key-value pair, like `ActionKV::insert`.
+    pub async fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.with_store(move |store| store.insert(&key, &value)).await
+    }
+
+    #[inline]
+    pub async fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        self.insert(key, value).await
+    }
+
+    /// Deletes `key`, like `ActionKV::delete`.
+    pub async fn delete(&self, key: &ByteStr) -> Result<(), KvError> {
+        let key = key.to_vec();
+        self.with_store(move |store| store.delete(&key)).await
+    }
+
+    /// Returns the live key-value pairs whose key starts with `prefix`, like `ActionKV::scan`.
+    pub async fn scan(&self, prefix: &ByteStr) -> Result<Vec<(ByteString, ByteString)>, KvError> {
+        let prefix = prefix.to_vec();
+        self.with_store(move |store| store.scan(&prefix)).await
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Eviction strategy used by a `ValueCache` once its byte budget is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Evicts the entry that was read least recently.
+    Lru,
+    /// Second-chance eviction: entries that were read since the hand last
+    /// passed them are skipped once before being evicted.
+    Clock,
+}
+
+/// Counters describing how effective the value cache has been.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub byte_budget: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: ByteString,
+    tick: u64,        // last access for LRU, insertion generation for CLOCK
+    referenced: bool, // second-chance bit, used by CLOCK
+}
+
+/// An in-memory cache of recently read values, bounded by the total number
+/// of key and value bytes it holds.
+#[derive(Debug)]
+pub struct ValueCache {
+    policy: CachePolicy,
+    byte_budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<ByteString, Entry>,
+    recency: BTreeMap<u64, ByteString>, // LRU order, oldest first
+    ring: VecDeque<(u64, ByteString)>,  // CLOCK hand starts at the front
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    /// Creates an empty cache that holds at most `byte_budget` bytes of keys and values.
+    pub fn new(policy: CachePolicy, byte_budget: usize) -> Self {
+        ValueCache {
+            policy,
+            byte_budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            ring: VecDeque::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Looks up `key`, counting the lookup as a hit or a miss.
+    pub fn get(&mut self, key: &ByteStr) -> Option<ByteString> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let entry = match self.entries.get_mut(key) {
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+            Some(entry) => entry,
+        };
+
+        self.stats.hits += 1;
+        match self.policy {
+            CachePolicy::Lru => {
+                let key = self.recency.remove(&entry.tick).expect("recency out of sync");
+                entry.tick = tick;
+                self.recency.insert(tick, key);
+            }
+            CachePolicy::Clock => entry.referenced = true,
+        }
+
+        Some(entry.value.clone())
+    }
+
+    /// Stores a value that was just read from disk. Values larger than the
+    /// whole budget are not cached.
+    pub fn put(&mut self, key: &ByteStr, value: &ByteStr) {
+        let cost = key.len() + value.len();
+        if cost > self.byte_budget {
+            return;
+        }
+
+        self.invalidate(key);
+
+        while self.used + cost > self.byte_budget {
+            if !self.evict_one() {
+                break;
+            }
+        }
+
+        self.tick += 1;
+        let entry = Entry {
+            value: value.to_vec(),
+            tick: self.tick,
+            referenced: false,
+        };
+
+        match self.policy {
+            CachePolicy::Lru => {
+                self.recency.insert(self.tick, key.to_vec());
+            }
+            CachePolicy::Clock => {
+                // invalidated keys leave stale slots behind, don't let them pile up
+                if self.ring.len() > 2 * self.entries.len() + 16 {
+                    let entries = &self.entries;
+                    self.ring.retain(|(tick, key)| entries.get(key).map(|e| e.tick) == Some(*tick));
+                }
+                self.ring.push_back((self.tick, key.to_vec()));
+            }
+        }
+
+        self.entries.insert(key.to_vec(), entry);
+        self.used += cost;
+    }
+
+    /// Drops `key` from the cache, if present. Called whenever the key is written.
+    pub fn invalidate(&mut self, key: &ByteStr) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used -= key.len() + entry.value.len();
+            if self.policy == CachePolicy::Lru {
+                self.recency.remove(&entry.tick);
+            }
+            // CLOCK leaves a stale slot in the ring, which is skipped when the hand reaches it
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.used,
+            byte_budget: self.byte_budget,
+            ..self.stats
+        }
+    }
+
+    fn evict_one(&mut self) -> bool {
+        let victim = match self.policy {
+            CachePolicy::Lru => match self.recency.pop_first() {
+                None => return false,
+                Some((_, key)) => key,
+            },
+            CachePolicy::Clock => loop {
+                let (tick, key) = match self.ring.pop_front() {
+                    None => return false,
+                    Some(slot) => slot,
+                };
+                match self.entries.get_mut(&key) {
+                    None => continue,
+                    Some(entry) if entry.tick != tick => continue, // stale slot left behind by invalidate()
+                    Some(entry) if entry.referenced => {
+                        entry.referenced = false;
+                        self.ring.push_back((tick, key));
+                    }
+                    Some(_) => break key,
+                }
+            },
+        };
+
+        if let Some(entry) = self.entries.remove(&victim) {
+            self.used -= victim.len() + entry.value.len();
+            self.stats.evictions += 1;
+        }
+
+        true
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crc::crc32;
+
+use crate::mmap::MappedFile;
+use crate::{ActionKV, CompactionPolicy, KvError};
+
+/// Caps the rate at which a compaction copies records, so it doesn't starve foreground I/O.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimit {
+    pub(crate) fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimit { bytes_per_sec, started: Instant::now(), bytes: 0 }
+    }
+
+    pub(crate) fn unlimited() -> Self {
+        RateLimit::new(None)
+    }
+
+    /// Accounts for `n` bytes of I/O, sleeping until they fit the rate.
+    fn consume(&mut self, n: u64) {
+        let bytes_per_sec = match self.bytes_per_sec {
+            None | Some(0) => return,
+            Some(rate) => rate,
+        };
+
+        self.bytes += n;
+        let due = Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+/// What a compaction copies: the records the indexes pointed to when it started.
+///
+/// Records below `end` never change, so they can be copied without holding the store.
+/// Records appended while the copy runs are carried over verbatim by `finish_compaction`.
+#[derive(Debug)]
+pub(crate) struct CompactionPlan {
+    positions: Vec<u64>, // ascending, so the copy keeps the order of the log
+    end: u64,
+    data_start: u64,
+    with_table: bool,
+    dead_bytes: u64,
+    dead_records: u64,
+    generation: u64,
+    source: PathBuf,
+    target: PathBuf,
+}
+
+/// Live records copied to the new file, keyed by their old position.
+#[derive(Debug)]
+pub(crate) struct CompactedFile {
+    file: File,
+    moved: HashMap<u64, u64>,
+}
+
+impl ActionKV {
+    /// Rewrites the file with only the records that are still referenced, dropping
+    /// overwritten values, tombstones and the records of dropped tables.
+    ///
+    /// The new file is written next to the old one and renamed over it once complete,
+    /// so a crash during compaction leaves the old file intact. Values in the cache stay
+    /// valid; a memory map made with `map` is remade over the new file.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes the file shrank by.
+    pub fn compact(&mut self) -> Result<u64, KvError> {
+        let before = self.file_len;
+
+        let plan = self.compaction_plan()?;
+        let compacted = ActionKV::copy_live_records(&plan, &mut RateLimit::unlimited())?;
+        self.finish_compaction(plan, compacted)?;
+
+        Ok(before.saturating_sub(self.file_len))
+    }
+
+    /// Bytes of records that no index refers to anymore: overwritten values, deleted
+    /// keys' old values and the records of dropped tables. Compaction reclaims them.
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes
+    }
+
+    /// The number of records that no index refers to anymore, see `dead_bytes`.
+    pub fn dead_records(&self) -> u64 {
+        self.dead_records
+    }
+
+    /// Share of the records' bytes that are dead, between 0 and 1.
+    pub(crate) fn dead_ratio(&self) -> f64 {
+        let log_len = self.file_len.saturating_sub(self.data_start);
+        if log_len == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / log_len as f64
+    }
+
+    pub(crate) fn set_write_throttle(&mut self, policy: Option<CompactionPolicy>) {
+        self.throttle = policy;
+    }
+
+    /// Makes a write owe a delay while a background compactor has fallen behind: a compaction
+    /// is due, and the file is as dead as the policy tolerates. The writer waits it out after
+    /// unlocking the store, see `take_write_delay`.
+    pub(crate) fn throttle_writes(&mut self) {
+        if let Some(policy) = &self.throttle {
+            if policy.is_due(self) && self.dead_ratio() >= policy.throttle_dead_ratio {
+                self.write_delay = Some(policy.throttle_delay);
+            }
+        }
+    }
+
+    /// The delay owed by the writes made since the last call, if any was throttled.
+    pub(crate) fn take_write_delay(&mut self) -> Option<Duration> {
+        self.write_delay.take()
+    }
+
+    /// Records what a compaction has to copy. Cheap, as it only reads the indexes.
+    pub(crate) fn compaction_plan(&self) -> Result<CompactionPlan, KvError> {
+        let mut positions: Vec<u64> = self
+            .index
+            .values()
+            .chain(self.tables.values().flat_map(|index| index.values()))
+            .copied()
+            .collect();
+        positions.sort_unstable();
+
+        let mut target = self.path.clone().into_os_string();
+        target.push(".compact");
+
+        Ok(CompactionPlan {
+            positions,
+            end: self.file_len,
+            data_start: self.data_start,
+            with_table: self.has_tables(),
+            dead_bytes: self.dead_bytes,
+            dead_records: self.dead_records,
+            generation: self.generation,
+            source: self.path.clone(),
+            target: PathBuf::from(target),
+        })
+    }
+
+    /// Copies the live records in `plan` to a new file. Reads through a handle of its own,
+    /// so it can run while other threads use the store.
+    pub(crate) fn copy_live_records(plan: &CompactionPlan, limit: &mut RateLimit) -> Result<CompactedFile, KvError> {
+        let copied = ActionKV::copy_records(plan, limit);
+        if copied.is_err() {
+            let _ = fs::remove_file(&plan.target); // a partial copy is of no use
+        }
+        copied
+    }
+
+    fn copy_records(plan: &CompactionPlan, limit: &mut RateLimit) -> Result<CompactedFile, KvError> {
+        let mut source = BufReader::new(File::open(&plan.source)?);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&plan.target)?;
+        let mut target = BufWriter::new(file);
+
+        // the file header is carried over as it is, so the format version doesn't change
+        let mut header = vec![0u8; plan.data_start as usize];
+        source.read_exact(&mut header)?;
+        target.write_all(&header)?;
+
+        let mut moved = HashMap::with_capacity(plan.positions.len());
+        let mut next = plan.data_start;
+
+        for &position in &plan.positions {
+            source.seek(SeekFrom::Start(position))?;
+            let kv = ActionKV::read_record(&mut source, position, plan.with_table)?;
+            if kv.value.is_empty() {
+                continue; // a tombstone: with the older values gone, there's nothing left to hide
+            }
+
+            let seed = ActionKV::checksum_seed(kv.table, plan.with_table);
+            let checksum = crc32::update(crc32::update(seed, &crc32::IEEE_TABLE, &kv.key), &crc32::IEEE_TABLE, &kv.value);
+            let (key_len, value_len) = (kv.key.len() as u32, kv.value.len() as u32);
+
+            ActionKV::write_record_header(&mut target, checksum, key_len, value_len, kv.table, plan.with_table)?;
+            target.write_all(&kv.key)?;
+            target.write_all(&kv.value)?;
+
+            let header_len = if plan.with_table { 16 } else { 12 };
+            let record_len = header_len + key_len as u64 + value_len as u64;
+            moved.insert(position, next);
+            next += record_len;
+
+            limit.consume(record_len * 2); // read once, written once
+        }
+
+        let file = target.into_inner().map_err(|err| err.into_error())?;
+
+        Ok(CompactedFile { file, moved })
+    }
+
+    /// Appends the records written since `plan` was made, points every index at the new
+    /// file and renames it over the old one.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `io::ErrorKind::Interrupted` if another compaction finished in the meantime;
+    /// the copy is discarded and the store is left as it was.
+    pub(crate) fn finish_compaction(&mut self, plan: CompactionPlan, compacted: CompactedFile) -> Result<(), KvError> {
+        let CompactedFile { mut file, moved } = compacted;
+
+        if plan.generation != self.generation {
+            drop(file);
+            fs::remove_file(&plan.target)?;
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "the store was compacted concurrently").into());
+        }
+
+        // records are only ever appended, so everything past `end` is new since the plan
+        let tail_start = file.seek(SeekFrom::End(0))?;
+        self.f.seek(SeekFrom::Start(plan.end))?;
+        let tail_len = io::copy(&mut self.f, &mut file)?;
+        file.sync_all()?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(KvError::Locked),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        let remap = self.mapped.is_some();
+        self.mapped = None; // the map must not outlive the file it covers
+        fs::rename(&plan.target, &self.path)?;
+        self.f = file; // drops the old file and its lock
+
+        let relocate = |position: u64| -> Option<u64> {
+            if position >= plan.end {
+                Some(position - plan.end + tail_start)
+            } else {
+                moved.get(&position).copied() // None for dropped tombstones
+            }
+        };
+
+        for index in std::iter::once(&mut self.index).chain(self.tables.values_mut()) {
+            index.retain(|_, position| match relocate(*position) {
+                Some(new_position) => {
+                    *position = new_position;
+                    true
+                }
+                None => false,
+            });
+        }
+
+        if remap {
+            self.mapped = Some(MappedFile::new(&self.f)?);
+        }
+
+        // everything dead at the start was left behind; what died since is still in the file
+        self.dead_bytes = self.dead_bytes.saturating_sub(plan.dead_bytes);
+        self.dead_records = self.dead_records.saturating_sub(plan.dead_records);
+        self.file_len = tail_start + tail_len;
+        self.generation += 1;
+
+        Ok(())
+    }
+}
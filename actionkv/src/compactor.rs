@@ -0,0 +1,162 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::compaction::RateLimit;
+use crate::{ActionKV, KvError, SharedActionKV};
+
+/// When a `Compactor` compacts the store, and how much it may slow everything else down.
+///
+/// The store is a single log file rather than a set of segments. What a segment count
+/// measures elsewhere, how much `load` has to read through besides the live data, is
+/// the number of dead records here, so that is the second trigger next to dead bytes.
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    /// Compact once at least this share of the file is dead...
+    pub min_dead_ratio: f64,
+    /// ...and at least this many bytes are, so small files aren't rewritten over and over.
+    pub min_dead_bytes: u64,
+    /// Also compact once this many records are dead, however few bytes they take, or `None` for no limit.
+    pub max_dead_records: Option<u64>,
+    /// Upper bound on the bytes read and written per second while copying, or `None` for no limit.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Once a compaction is due and this share of the file is dead, the compactor is falling
+    /// behind: every write waits `throttle_delay` after it has released the lock, so the file
+    /// can't outgrow the disk.
+    pub throttle_dead_ratio: f64,
+    pub throttle_delay: Duration,
+    /// How often the thresholds are checked.
+    pub check_interval: Duration,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            min_dead_ratio: 0.5,
+            min_dead_bytes: 1 << 20,
+            max_dead_records: None,
+            max_bytes_per_sec: None,
+            throttle_dead_ratio: 0.8,
+            throttle_delay: Duration::from_millis(1),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub(crate) fn is_due(&self, store: &ActionKV) -> bool {
+        let dead_bytes = store.dead_bytes() >= self.min_dead_bytes && store.dead_ratio() >= self.min_dead_ratio;
+        dead_bytes || self.max_dead_records.is_some_and(|max| store.dead_records() >= max)
+    }
+}
+
+/// A background thread that compacts a `SharedActionKV` whenever its `CompactionPolicy` says so.
+///
+/// The live records are copied without holding the store's lock; it is only taken to plan a
+/// compaction and to switch over to the new file, which also picks up the records written
+/// while the copy ran. While the compactor runs, writes are throttled as the policy describes;
+/// a throttled writer waits once its `StoreGuard` is dropped, without holding the lock.
+///
+/// The thread stops when the compactor is dropped or `stop` is called, after finishing the
+/// compaction in progress, if any.
+#[derive(Debug)]
+pub struct Compactor {
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    compactions: Arc<AtomicU64>,
+    handle: Option<JoinHandle<Result<(), KvError>>>,
+}
+
+impl Compactor {
+    pub fn start(store: &SharedActionKV, policy: CompactionPolicy) -> Self {
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let compactions = Arc::new(AtomicU64::new(0));
+
+        // in place before start returns, so that every write from then on is throttled
+        store.lock().set_write_throttle(Some(policy.clone()));
+
+        let handle = {
+            let (store, shutdown, compactions) = (store.clone(), Arc::clone(&shutdown), Arc::clone(&compactions));
+            thread::Builder::new()
+                .name("actionkv-compactor".to_string())
+                .spawn(move || {
+                    let result = Compactor::run(&store, &policy, &shutdown, &compactions);
+
+                    store.lock().set_write_throttle(None);
+                    result
+                })
+                .expect("failed to spawn the compactor thread")
+        };
+
+        Compactor { shutdown, compactions, handle: Some(handle) }
+    }
+
+    /// The number of compactions completed so far.
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    /// Stops the thread and waits for it to exit.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error that made the thread give up early, if any. A failed compaction
+    /// leaves the store as it was before it started.
+    pub fn stop(mut self) -> Result<(), KvError> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), KvError> {
+        let (stopped, wake) = &*self.shutdown;
+        *stopped.lock().expect("compactor mutex poisoned") = true;
+        wake.notify_all();
+
+        match self.handle.take() {
+            None => Ok(()),
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("the compactor thread panicked").into())),
+        }
+    }
+
+    fn run(store: &SharedActionKV, policy: &CompactionPolicy, shutdown: &(Mutex<bool>, Condvar), compactions: &AtomicU64) -> Result<(), KvError> {
+        let (stopped, wake) = shutdown;
+
+        loop {
+            let guard = stopped.lock().expect("compactor mutex poisoned");
+            let (guard, _) = wake
+                .wait_timeout_while(guard, policy.check_interval, |stopped| !*stopped)
+                .expect("compactor mutex poisoned");
+            if *guard {
+                return Ok(());
+            }
+            drop(guard);
+
+            let plan = {
+                let store = store.lock();
+                if !policy.is_due(&store) {
+                    continue;
+                }
+                store.compaction_plan()?
+            };
+
+            let compacted = ActionKV::copy_live_records(&plan, &mut RateLimit::new(policy.max_bytes_per_sec))?;
+
+            match store.lock().finish_compaction(plan, compacted) {
+                Ok(()) => {
+                    compactions.fetch_add(1, Ordering::Relaxed);
+                }
+                // someone called `compact` in the meantime, check again next time
+                Err(KvError::Io(err)) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        let _ = self.shut_down();
+    }
+}
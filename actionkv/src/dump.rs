@@ -0,0 +1,317 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{ActionKV, KvError};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// The file formats `ActionKV::export` writes and `ActionKV::import` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One `{"key": ..., "value": ...}` object per line.
+    JsonLines,
+    /// A `key,value` header followed by one row per pair.
+    Csv,
+    /// `INSERT` statements for a `kv` table that SQLite can run as they are.
+    Sql,
+}
+
+/// How keys and values, which are arbitrary bytes, are written as text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+    /// Written as UTF-8, with invalid sequences replaced by U+FFFD. Doesn't round-trip binary data.
+    Utf8Lossy,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DumpFormat::JsonLines),
+            "csv" => Ok(DumpFormat::Csv),
+            "sql" => Ok(DumpFormat::Sql),
+            _ => Err(format!("unknown format {:?}, expected jsonl, csv or sql", s)),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpFormat::JsonLines => write!(f, "jsonl"),
+            DumpFormat::Csv => write!(f, "csv"),
+            DumpFormat::Sql => write!(f, "sql"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            "utf8-lossy" => Ok(Encoding::Utf8Lossy),
+            _ => Err(format!("unknown encoding {:?}, expected hex, base64 or utf8-lossy", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Hex => write!(f, "hex"),
+            Encoding::Base64 => write!(f, "base64"),
+            Encoding::Utf8Lossy => write!(f, "utf8-lossy"),
+        }
+    }
+}
+
+impl Encoding {
+    /// Writes `bytes` as text in this encoding.
+    pub fn encode(self, bytes: &ByteStr) -> String {
+        match self {
+            Encoding::Hex => hex::encode(bytes),
+            Encoding::Base64 => BASE64.encode(bytes),
+            Encoding::Utf8Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// Reads back bytes written by `encode`.
+    pub fn decode(self, text: &str) -> Result<ByteString, String> {
+        match self {
+            Encoding::Hex => hex::decode(text).map_err(|err| err.to_string()),
+            Encoding::Base64 => BASE64.decode(text).map_err(|err| err.to_string()),
+            Encoding::Utf8Lossy => Ok(text.as_bytes().to_vec()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: String,
+}
+
+const SQL_TABLE: &str = "CREATE TABLE IF NOT EXISTS kv (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL);";
+
+// a malformed dump is reported like a malformed store file: as bad data
+fn invalid_dump(record: usize, reason: impl fmt::Display) -> KvError {
+    let message = format!("record {}: {}", record, reason);
+    KvError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn sql_literal(bytes: &ByteStr, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Hex => format!("X'{}'", hex::encode(bytes)), // a blob, so the bytes survive as they are
+        _ => format!("'{}'", encoding.encode(bytes).replace('\'', "''")),
+    }
+}
+
+/// Reads one `;`-terminated statement. A `;` inside a string literal doesn't end it:
+/// a statement is complete once its quotes are balanced, `''` escapes included.
+fn read_statement<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut statement = Vec::new();
+    loop {
+        let read = input.read_until(b';', &mut statement)?;
+        let quotes = statement.iter().filter(|&&b| b == b'\'').count();
+        if read == 0 || (statement.last() == Some(&b';') && quotes % 2 == 0) {
+            break;
+        }
+    }
+
+    let statement = String::from_utf8(statement).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if statement.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(statement))
+}
+
+/// Parses the `X'..'` or `'..'` literal at the start of `sql`, returning its bytes and the rest.
+fn parse_sql_literal(sql: &str, encoding: Encoding) -> Result<(ByteString, &str), String> {
+    let sql = sql.trim_start();
+    if let Some(rest) = sql.strip_prefix("X'").or_else(|| sql.strip_prefix("x'")) {
+        let end = rest.find('\'').ok_or("unterminated blob literal")?;
+        let bytes = hex::decode(&rest[..end]).map_err(|err| err.to_string())?;
+        return Ok((bytes, &rest[end + 1..]));
+    }
+
+    let rest = sql.strip_prefix('\'').ok_or("expected a string or blob literal")?;
+    let mut text = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\'' {
+            text.push(c);
+        } else if rest[i + 1..].starts_with('\'') {
+            text.push('\'');
+            chars.next();
+        } else {
+            return Ok((encoding.decode(&text)?, &rest[i + 1..]));
+        }
+    }
+
+    Err("unterminated string literal".to_string())
+}
+
+/// Extracts the key and value from an `INSERT ... VALUES (key, value);` statement.
+fn parse_sql_insert(statement: &str, encoding: Encoding) -> Result<(ByteString, ByteString), String> {
+    let values_at = statement.to_ascii_uppercase().find("VALUES").ok_or("expected VALUES")?;
+    let rest = statement[values_at + "VALUES".len()..].trim_start();
+    let rest = rest.strip_prefix('(').ok_or("expected (")?;
+
+    let (key, rest) = parse_sql_literal(rest, encoding)?;
+    let rest = rest.trim_start().strip_prefix(',').ok_or("expected ,")?;
+    let (value, rest) = parse_sql_literal(rest, encoding)?;
+    rest.trim_start().strip_prefix(')').ok_or("expected )")?;
+
+    Ok((key, value))
+}
+
+impl ActionKV {
+    /// Writes every live key-value pair of the default table to `output`, ordered by key.
+    /// Values are read one at a time, so the store doesn't have to fit in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Where the dump is written.
+    /// * `format` - The file format of the dump.
+    /// * `encoding` - How keys and values are written as text. SQL dumps made with
+    ///   `Encoding::Hex` use blob literals, so they hold the raw bytes.
+    ///
+    /// # Returns
+    ///
+    /// The number of pairs written.
+    pub fn export<W: Write>(&mut self, output: W, format: DumpFormat, encoding: Encoding) -> Result<usize, KvError> {
+        let mut output = io::BufWriter::new(output);
+
+        let written = match format {
+            DumpFormat::JsonLines => self.for_each_live(|key, value| {
+                let record = JsonRecord { key: encoding.encode(key), value: encoding.encode(value) };
+                serde_json::to_writer(&mut output, &record).map_err(io::Error::from)?;
+                writeln!(output)?;
+                Ok(())
+            })?,
+
+            DumpFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(&mut output);
+                writer.write_record(["key", "value"]).map_err(io::Error::from)?;
+                let written = self.for_each_live(|key, value| {
+                    let record = [encoding.encode(key), encoding.encode(value)];
+                    writer.write_record(&record).map_err(io::Error::from)?;
+                    Ok(())
+                })?;
+                writer.flush()?;
+                written
+            }
+
+            DumpFormat::Sql => {
+                writeln!(output, "BEGIN TRANSACTION;")?;
+                writeln!(output, "{}", SQL_TABLE)?;
+                let written = self.for_each_live(|key, value| {
+                    let (key, value) = (sql_literal(key, encoding), sql_literal(value, encoding));
+                    writeln!(output, "INSERT OR REPLACE INTO kv (key, value) VALUES ({}, {});", key, value)?;
+                    Ok(())
+                })?;
+                writeln!(output, "COMMIT;")?;
+                written
+            }
+        };
+        output.flush()?;
+
+        Ok(written)
+    }
+
+    /// Calls `f` with every live key-value pair of the default table, ordered by key,
+    /// reading one value at a time. Returns how many pairs there were.
+    fn for_each_live<F>(&mut self, mut f: F) -> Result<usize, KvError>
+    where
+        F: FnMut(&ByteStr, &ByteStr) -> Result<(), KvError>,
+    {
+        let mut keys: Vec<ByteString> = self.index.keys().cloned().collect();
+        keys.sort();
+
+        let mut count = 0;
+        for key in keys {
+            match self.get(&key)? {
+                Some(value) if !value.is_empty() => f(&key, &value)?,
+                _ => continue, // deleted
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Inserts every key-value pair read from `input` into the default table, overwriting
+    /// existing keys. The input is read one record at a time, so it can be of any size.
+    ///
+    /// SQL input is expected to look like the dumps written by `export`: statements other
+    /// than `INSERT` are skipped, and blob literals are taken as raw bytes whatever `encoding` is.
+    ///
+    /// # Returns
+    ///
+    /// The number of pairs inserted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Io` with `io::ErrorKind::InvalidData` for a malformed record. The
+    /// records before it have already been inserted.
+    pub fn import<R: BufRead>(&mut self, mut input: R, format: DumpFormat, encoding: Encoding) -> Result<usize, KvError> {
+        let mut imported = 0;
+
+        match format {
+            DumpFormat::JsonLines => {
+                for (i, line) in input.lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let record: JsonRecord = serde_json::from_str(&line).map_err(|err| invalid_dump(i + 1, err))?;
+                    let key = encoding.decode(&record.key).map_err(|err| invalid_dump(i + 1, err))?;
+                    let value = encoding.decode(&record.value).map_err(|err| invalid_dump(i + 1, err))?;
+                    self.insert(&key, &value)?;
+                    imported += 1;
+                }
+            }
+
+            DumpFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(input);
+                for (i, record) in reader.records().enumerate() {
+                    let record = record.map_err(|err| invalid_dump(i + 1, err))?;
+                    if record.len() != 2 {
+                        return Err(invalid_dump(i + 1, "expected a key and a value"));
+                    }
+                    let key = encoding.decode(&record[0]).map_err(|err| invalid_dump(i + 1, err))?;
+                    let value = encoding.decode(&record[1]).map_err(|err| invalid_dump(i + 1, err))?;
+                    self.insert(&key, &value)?;
+                    imported += 1;
+                }
+            }
+
+            DumpFormat::Sql => {
+                let mut i = 0;
+                while let Some(statement) = read_statement(&mut input)? {
+                    i += 1;
+                    if !statement.trim_start().to_ascii_uppercase().starts_with("INSERT") {
+                        continue;
+                    }
+                    let (key, value) = parse_sql_insert(&statement, encoding).map_err(|err| invalid_dump(i, err))?;
+                    self.insert(&key, &value)?;
+                    imported += 1;
+                }
+            }
+        }
+
+        Ok(imported)
+    }
+}
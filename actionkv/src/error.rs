@@ -0,0 +1,95 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading or writing an ActionKV store.
+#[derive(Debug)]
+pub enum KvError {
+    /// The checksum stored in the record at `offset` doesn't match its contents.
+    Corruption { offset: u64, expected: u32, actual: u32 },
+    /// The underlying file could not be read or written.
+    Io(io::Error),
+    /// Keys are stored with a u32 length, so they can't be longer than `u32::MAX` bytes.
+    KeyTooLarge { len: u64 },
+    /// Values are stored with a u32 length, so they can't be longer than `u32::MAX` bytes.
+    ValueTooLarge { len: u64 },
+    /// Another process (or another handle in this one) holds the lock on the store file.
+    Locked,
+    /// The file was written by a version of ActionKV this build can't read.
+    VersionMismatch { found: u32, supported: u32 },
+    /// The file was written by a format version without tables, so only the default table is available.
+    TablesUnsupported { version: u32 },
+    /// No secondary index with this name has been registered.
+    NoSuchIndex { name: String },
+    /// Table names starting with `+index:` hold the entries of secondary indexes.
+    ReservedTableName { name: String },
+    /// The table with this id has been dropped.
+    NoSuchTable { id: u32 },
+    /// A transaction kept finding `key`, which it had read, changed by another writer at commit time.
+    TransactionConflict { key: Vec<u8> },
+    /// A conditional write found a different value than expected. Holds the value
+    /// that is currently stored, or `None` if the key is absent.
+    Conflict { current: Option<Vec<u8>> },
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::Corruption { offset, expected, actual } => write!(
+                f,
+                "data corruption at offset {} (checksum {:08x} != {:08x})",
+                offset, actual, expected
+            ),
+            KvError::Io(err) => write!(f, "I/O error: {}", err),
+            KvError::KeyTooLarge { len } => {
+                write!(f, "key of {} bytes exceeds the maximum of {} bytes", len, u32::MAX)
+            }
+            KvError::ValueTooLarge { len } => {
+                write!(f, "value of {} bytes exceeds the maximum of {} bytes", len, u32::MAX)
+            }
+            KvError::Locked => write!(f, "store is locked by another writer"),
+            KvError::VersionMismatch { found, supported } => write!(
+                f,
+                "unsupported file format version {} (this build reads up to version {})",
+                found, supported
+            ),
+            KvError::TablesUnsupported { version } => {
+                write!(f, "file format version {} has no support for tables", version)
+            }
+            KvError::NoSuchIndex { name } => write!(f, "no secondary index called {:?}", name),
+            KvError::ReservedTableName { name } => {
+                write!(f, "table name {:?} is reserved for secondary indexes", name)
+            }
+            KvError::NoSuchTable { id } => write!(f, "table {} has been dropped", id),
+            KvError::TransactionConflict { key } => {
+                write!(f, "transaction conflict: {:?} was changed by another writer", key)
+            }
+            KvError::Conflict { current: None } => write!(f, "conditional write failed: key is absent"),
+            KvError::Conflict { current: Some(value) } => {
+                write!(f, "conditional write failed: key currently holds {:?}", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvError {
+    fn from(err: io::Error) -> Self {
+        KvError::Io(err)
+    }
+}
+
+impl KvError {
+    /// True when the error is an end-of-file hit while reading a record, which marks
+    /// the end of the log (or a record that was only partially written).
+    pub fn is_eof(&self) -> bool {
+        matches!(self, KvError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
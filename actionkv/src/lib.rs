@@ -1,17 +1,62 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
 use std::io::{self, BufReader, SeekFrom, Seek, Read, BufWriter, Write};
+use std::time::Duration;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32;
 use serde_derive::{Deserialize, Serialize};
 
+#[cfg(feature = "async")]
+mod async_kv;
+mod cache;
+mod compaction;
+mod compactor;
+mod dump;
+mod error;
+mod mmap;
+mod secondary;
+mod shared;
+mod stats;
+mod stream;
+mod table;
+mod transaction;
+
+#[cfg(feature = "async")]
+pub use async_kv::AsyncActionKV;
+pub use cache::{CachePolicy, CacheStats};
+pub use compactor::{CompactionPolicy, Compactor};
+pub use dump::{DumpFormat, Encoding};
+pub use error::KvError;
+pub use stats::{Bucket, SizeHistogram, StoreStats};
+pub use stream::ValueReader;
+pub use shared::{SharedActionKV, StoreGuard};
+pub use table::Table;
+pub use transaction::{Transaction, TRANSACTION_ATTEMPTS};
+use cache::ValueCache;
+use mmap::MappedFile;
+use secondary::{SecondaryIndex, INDEX_TABLE_PREFIX};
+
 type ByteString = Vec<u8>; // String in the form of raw bytes
 type ByteStr = [u8]; // str in the form of raw bytes
 
+const FORMAT_MAGIC: &[u8; 4] = b"akv\0"; // first bytes of every file created since format version 1
+const FORMAT_VERSION: u32 = 2;
+const TABLES_VERSION: u32 = 2; // first format version with a table id in every record
+const FILE_HEADER_LEN: u64 = 8; // magic + u32 version
+
+/// The table used by `ActionKV`'s own get/insert/update/delete methods.
+pub const DEFAULT_TABLE: u32 = 0;
+const CATALOG_TABLE: u32 = u32::MAX; // maps table names to table ids, a tombstone marks a dropped table
+const INTERNAL_TABLE_PREFIX: &str = "+"; // tables for bookkeeping rather than user data, like secondary indexes
+const MAX_PREALLOCATION: u64 = 64 * 1024; // lengths read from the file aren't trusted with bigger allocations
+const STREAMING_CHECKSUM: u32 = 0; // placeholder held by a streamed record until its value is complete
+
 #[derive(Debug, Serialize, Deserialize)] // generate serialized code to write k, v pairs to disk
 pub struct KeyValuePair {
+    pub table: u32,
     pub key: ByteString,
     pub value: ByteString,
 }
@@ -19,36 +64,163 @@ pub struct KeyValuePair {
 #[derive(Debug)]
 pub struct ActionKV {
     f: File,
-    pub index: HashMap<ByteString, u64> // mapping b/w keys and file locations
+    path: PathBuf, // compaction writes a new file next to it and renames it into place
+    pub index: HashMap<ByteString, u64>, // mapping b/w keys and file locations, for the default table
+    tables: HashMap<u32, HashMap<ByteString, u64>>, // indexes of the catalog and every named table
+    table_ids: HashMap<String, u32>, // names of the tables that haven't been dropped
+    next_table_id: u32, // ids are never reused, so records of dropped tables stay dead
+    secondary: HashMap<String, SecondaryIndex>, // indexes over the values of the default table
+    cache: Option<ValueCache>, // recently read values, skips the disk for hot keys
+    mapped: Option<MappedFile>, // read-only map of the file as it was when `map` was last called
+    data_start: u64, // offset of the first record, 0 for files that predate the header
+    version: u32, // format version of the file, 0 for files that predate the header
+    file_len: u64, // kept up to date by every write, so that writes never have to ask the file system
+    dead_bytes: u64, // bytes of records that are no longer referenced by any index
+    dead_records: u64, // how many records those are
+    generation: u64, // bumped by every compaction, which moves every record
+    throttle: Option<CompactionPolicy>, // set while a background compactor is running
+    write_delay: Option<Duration>, // owed by a throttled write, waited out once the store is unlocked
 }
 
 impl ActionKV {
     /// Opens a file at the specified path and returns a new instance of ActionKV 
     /// initialized with the file and an empty index.
     ///
+    /// New files start with a small header recording the format version. Files
+    /// without a header, written before the header existed, are still accepted.
+    ///
     /// # Arguments
     ///
     /// * path - A reference to a Path type representing the path to the file to open.
     ///
     /// # Returns
     ///
-    /// An io::Result containing a new instance of ActionKV initialized with the file at path and an empty index if the
+    /// A Result containing a new instance of ActionKV initialized with the file at path and an empty index if the
     /// operation was successful.
-    pub fn open(path: &Path) -> io::Result<Self> {
-        let f = OpenOptions::new()
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Locked` if another handle already has the file open, and
+    /// `KvError::VersionMismatch` if the file was written by a newer format version.
+    pub fn open(path: &Path) -> Result<Self, KvError> {
+        let mut f = OpenOptions::new()
                         .read(true)
-                        .write(true)
+                        .write(true) // not append: insert_from_reader patches the checksum in place
                         .create(true)
-                        .append(true)
+                        .truncate(false)
                         .open(path)?;
+
+        match f.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(KvError::Locked),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        let (data_start, version) = ActionKV::read_file_header(&mut f)?;
+        let file_len = f.metadata()?.len();
         let index = HashMap::new();
 
-        Ok(ActionKV { f, index })
+        Ok(ActionKV {
+            f,
+            path: path.to_path_buf(),
+            index,
+            tables: HashMap::new(),
+            table_ids: HashMap::new(),
+            next_table_id: DEFAULT_TABLE + 1,
+            secondary: HashMap::new(),
+            cache: None,
+            mapped: None,
+            data_start,
+            version,
+            file_len,
+            dead_bytes: 0,
+            dead_records: 0,
+            generation: 0,
+            throttle: None,
+            write_delay: None,
+        })
+    }
+
+    /// Checks the format header at the start of `f`, writing one if the file is empty.
+    /// Returns the offset of the first record and the format version.
+    fn read_file_header(f: &mut File) -> Result<(u64, u32), KvError> {
+        let file_len = f.metadata()?.len();
+
+        if file_len == 0 {
+            f.write_all(FORMAT_MAGIC)?;
+            f.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+            return Ok((FILE_HEADER_LEN, FORMAT_VERSION));
+        }
+
+        if file_len < FILE_HEADER_LEN {
+            // too short to hold a header or a complete record: a crash cut off the
+            // header of a new file, so start it over
+            f.set_len(0)?;
+            f.seek(SeekFrom::Start(0))?;
+            f.write_all(FORMAT_MAGIC)?;
+            f.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+            return Ok((FILE_HEADER_LEN, FORMAT_VERSION));
+        }
+
+        let mut magic = [0u8; 4];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut magic)?;
+        if &magic != FORMAT_MAGIC {
+            return Ok((0, 0)); // headerless file from before format version 1
+        }
+
+        let version = f.read_u32::<LittleEndian>()?;
+        if version > FORMAT_VERSION {
+            return Err(KvError::VersionMismatch { found: version, supported: FORMAT_VERSION });
+        }
+
+        Ok((FILE_HEADER_LEN, version))
+    }
+
+    /// Whether the file supports named tables. Files older than format version 2 have
+    /// no table ids; all of their records belong to the default table.
+    pub fn has_tables(&self) -> bool {
+        self.version >= TABLES_VERSION
+    }
+
+    /// Length of the fixed-size part of a record: checksum, key length, value length and table id.
+    fn record_header_len(&self) -> u64 {
+        if self.has_tables() { 16 } else { 12 }
+    }
+
+    /// Initial value of a record's checksum. From format version 2 on, the table id is covered too.
+    pub(crate) fn checksum_seed(table: u32, with_table: bool) -> u32 {
+        if with_table {
+            crc32::checksum_ieee(&table.to_le_bytes())
+        } else {
+            0
+        }
+    }
+
+    /// Enables an in-memory cache of values returned by `get`. Cached values are
+    /// dropped whenever their key is inserted, updated or deleted.
+    ///
+    /// # Arguments
+    ///
+    /// * policy - The eviction strategy to use once the cache is full.
+    /// * byte_budget - The maximum number of key and value bytes held by the cache.
+    ///
+    /// # Returns
+    ///
+    /// The same ActionKV, with an empty cache.
+    pub fn with_cache(mut self, policy: CachePolicy, byte_budget: usize) -> Self {
+        self.cache = Some(ValueCache::new(policy, byte_budget));
+        self
+    }
+
+    /// Returns the hit/miss counters of the value cache, or `None` if caching is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
     }
 
     /// Reads a key-value pair from a file and returns it as a KeyValuePair.
-    /// The function reads the checksum, key length, value length, and data from f, verifies that the checksum
-    /// matches the computed checksum of the data.
+    /// The function reads the checksum, key length, value length, table id and data from f, verifies that the checksum
+    /// matches the computed checksum of the data. Records are expected in the current format version.
     ///
     /// # Type parameters
     ///
@@ -60,67 +232,143 @@ impl ActionKV {
     ///
     /// # Returns
     ///
-    /// An io::Result containing a KeyValuePair representing the key-value pair read from the file if the operation
+    /// A Result containing a KeyValuePair representing the key-value pair read from the file if the operation
     /// was successful. 
-    pub fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Corruption` if the checksum doesn't match, and an `UnexpectedEof`
+    /// I/O error if the file ends before the record does. A plain reader has no position,
+    /// so the offset of a `Corruption` is always 0, the start of the record.
+    pub fn process_record<R: Read>(f: &mut R) -> Result<KeyValuePair, KvError> {
+        ActionKV::read_record(f, 0, true)
+    }
+
+    /// Reads a record written with or without a table id, see `has_tables`.
+    /// `offset` is where the record starts, for error reports.
+    fn read_record<R: Read>(f: &mut R, offset: u64, with_table: bool) -> Result<KeyValuePair, KvError> {
         let saved_checksum = f.read_u32::<LittleEndian>()?;
         let key_len = f.read_u32::<LittleEndian>()?;
         let val_len = f.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
+        let table = if with_table { f.read_u32::<LittleEndian>()? } else { DEFAULT_TABLE };
+        let data_len = key_len as u64 + val_len as u64; // each fits a u32, their sum may not
 
-        let mut data = ByteString::with_capacity(data_len as usize);
+        // a corrupt length can claim gigabytes; the buffer only grows as the file delivers bytes
+        let mut data = ByteString::with_capacity(data_len.min(MAX_PREALLOCATION) as usize);
 
         // f.by_ref() is required because take(n) creates a new Read value
         // using a reference within this short-lived block sidesteps ownership issues.
         {
             f.by_ref()
-            .take(data_len as u64)
+            .take(data_len)
             .read_to_end(&mut data)?;
         }
 
-        debug_assert_eq!(data.len(), data_len as usize); // test runs only in debug build
+        if data.len() as u64 != data_len {
+            // the record was cut short, e.g. by a crash halfway through a write
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
-        let checksum = crc32::checksum_ieee(&data); // checksum (a number) verifies that the bytes read from disk are the same as what was intended
+        let seed = ActionKV::checksum_seed(table, with_table);
+        let checksum = crc32::update(seed, &crc32::IEEE_TABLE, &data); // checksum (a number) verifies that the bytes read from disk are the same as what was intended
         if checksum != saved_checksum {
-            panic!("data corruption encountered ({:08x} != {:08x})", checksum, saved_checksum);
+            return Err(KvError::Corruption { offset, expected: saved_checksum, actual: checksum });
         }
 
         let value = data.split_off(key_len as usize);
         let key = data;
 
-        Ok(KeyValuePair { key, value })
+        Ok(KeyValuePair { table, key, value })
     }
 
     /// Reads key-value pairs from a file and creates an index for each key-value pair.
     ///
+    /// A record cut short at the end of the file, left behind by a crash during a write,
+    /// is removed so that later writes don't end up behind it. So is a last record that
+    /// still has the placeholder checksum of `insert_from_reader`, which never completed.
+    /// A short record with valid records right after it (looked for up to 64 KiB past its
+    /// header) has a corrupt length instead: that's a `KvError::Corruption`, and the file
+    /// is left as it is.
+    ///
     /// # Arguments
     ///
     /// None.
     ///
     /// # Returns
     ///
-    /// An io::Result that indicates whether the operation was successful.
-    pub fn load(&mut self) -> io::Result<()> {
+    /// A Result that indicates whether the operation was successful.
+    pub fn load(&mut self) -> Result<(), KvError> {
+        let with_table = self.has_tables();
+        let header_len = self.record_header_len();
+        let mut record_lens: HashMap<u64, u64> = HashMap::new(); // lengths of the records the indexes point to
+        let mut records: u64 = 0;
+        let file_len = self.f.metadata()?.len();
         let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(self.data_start))?;
 
-        loop {
-            let position = f.seek(SeekFrom::Current(0))?; // returns the number of bytes from the start of the file which becomes the index
+        let end_of_log = loop {
+            let position = f.stream_position()?; // returns the number of bytes from the start of the file which becomes the index
 
-            let maybe_kv = ActionKV::process_record(&mut f);
+            let maybe_kv = ActionKV::read_record(&mut f, position, with_table);
 
             let kv = match maybe_kv {
                 Ok(kv) => kv,
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        }
-                        _ => return Err(err),
+                Err(err) if err.is_eof() => {
+                    if position < file_len {
+                        MappedFile::new(f.get_ref())?.check_torn_record(position, with_table)?;
+                    }
+                    break position;
+                }
+                Err(KvError::Corruption { expected: STREAMING_CHECKSUM, .. }) if f.stream_position()? == file_len => {
+                    break position; // a streamed write that crashed before its checksum was written
+                }
+                Err(err) => return Err(err),
+            };
+
+            if kv.table == CATALOG_TABLE {
+                let name = String::from_utf8_lossy(&kv.key).into_owned();
+                match <[u8; 4]>::try_from(kv.value.as_slice()) {
+                    Ok(id) => {
+                        let id = u32::from_le_bytes(id);
+                        self.next_table_id = self.next_table_id.max(id.saturating_add(1));
+                        self.table_ids.insert(name, id);
+                    }
+                    Err(_) => {
+                        self.table_ids.remove(&name); // tombstone written by drop_table
                     }
                 }
+            }
+
+            records += 1;
+            record_lens.insert(position, header_len + kv.key.len() as u64 + kv.value.len() as u64);
+
+            let replaced = match kv.table {
+                DEFAULT_TABLE => self.index.insert(kv.key, position),
+                table => self.tables.entry(table).or_default().insert(kv.key, position),
             };
-            self.index.insert(kv.key, position);
+            if let Some(replaced) = replaced {
+                record_lens.remove(&replaced);
+            }
+        };
+
+        if end_of_log < file_len {
+            self.mapped = None; // the map must never cover bytes that are about to disappear
+            self.f.set_len(end_of_log)?;
         }
+        self.file_len = end_of_log;
+
+        // records of dropped tables are garbage
+        let live: Vec<u32> = self.table_ids.values().copied().collect();
+        self.tables.retain(|table, _| *table == CATALOG_TABLE || live.contains(table));
+
+        let live_lens: Vec<u64> = self
+            .index
+            .values()
+            .chain(self.tables.values().flat_map(|index| index.values()))
+            .filter_map(|position| record_lens.get(position).copied())
+            .collect();
+        self.dead_bytes = (end_of_log - self.data_start).saturating_sub(live_lens.iter().sum());
+        self.dead_records = records.saturating_sub(live_lens.len() as u64);
 
         Ok(())
     }
@@ -134,11 +382,12 @@ impl ActionKV {
     ///
     /// # Returns
     ///
-    /// An io::Result containing the key-value pair stored at the specified byte offset position if the operation was successful.
-    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
+    /// A Result containing the key-value pair stored at the specified byte offset position if the operation was successful.
+    pub fn get_at(&mut self, position: u64) -> Result<KeyValuePair, KvError> {
+        let with_table = self.has_tables();
         let mut f = BufReader::new(&mut self.f);
         f.seek(SeekFrom::Start(position))?; // seek to position
-        let kv = ActionKV::process_record(&mut f)?;
+        let kv = ActionKV::read_record(&mut f, position, with_table)?;
 
         Ok(kv)
     }
@@ -155,16 +404,160 @@ impl ActionKV {
     ///
     /// If the key does not exist in the database, returns `Ok(None)`.
     ///
-    /// If there is an I/O error, returns `Err(io::Error)`.
+    /// If the record can't be read, returns `Err(KvError)`.
+    ///
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        self.get_in(DEFAULT_TABLE, key)
+    }
+
+    /// Returns the checksum stored with the current record of `key`, without reading
+    /// the value. It changes whenever the value does, so it can serve as a version tag.
+    ///
+    /// # Returns
     ///
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+    /// `Ok(None)` if the key does not exist.
+    pub fn checksum(&mut self, key: &ByteStr) -> Result<Option<u32>, KvError> {
         let position = match self.index.get(key) {
             None => return Ok(None),
             Some(position) => *position,
         };
 
+        self.f.seek(SeekFrom::Start(position))?;
+        let checksum = self.f.read_u32::<LittleEndian>()?;
+
+        Ok(Some(checksum))
+    }
+
+    /// Returns every live key-value pair of the default table whose key starts with
+    /// `prefix`, ordered by key. An empty prefix matches every key.
+    pub fn scan(&mut self, prefix: &ByteStr) -> Result<Vec<(ByteString, ByteString)>, KvError> {
+        let mut keys: Vec<ByteString> = self.index.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        keys.sort();
+
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get(&key)? {
+                Some(value) if !value.is_empty() => found.push((key, value)),
+                _ => continue, // deleted
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// The size of the store file in bytes, its header included.
+    pub fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    /// Returns every live key of the default table that starts with `prefix`, ordered.
+    /// Unlike `scan`, no values are read: only the value length in each record's header,
+    /// which tells deleted keys apart.
+    pub fn keys(&mut self, prefix: &ByteStr) -> Result<Vec<ByteString>, KvError> {
+        let mut keys: Vec<(ByteString, u64)> = self.index.iter().filter(|(key, _)| key.starts_with(prefix)).map(|(key, position)| (key.clone(), *position)).collect();
+        keys.sort();
+
+        let mut live = Vec::with_capacity(keys.len());
+        for (key, position) in keys {
+            self.f.seek(SeekFrom::Start(position + 8))?; // the value length follows the checksum and the key length
+            if self.f.read_u32::<LittleEndian>()? > 0 {
+                live.push(key);
+            }
+        }
+
+        Ok(live)
+    }
+
+    pub(crate) fn table_index(&self, table: u32) -> Option<&HashMap<ByteString, u64>> {
+        match table {
+            DEFAULT_TABLE => Some(&self.index),
+            table => self.tables.get(&table),
+        }
+    }
+
+    // only ever creates the catalog's index: `check_table` turns writes to dropped tables away
+    fn table_index_mut(&mut self, table: u32) -> &mut HashMap<ByteString, u64> {
+        match table {
+            DEFAULT_TABLE => &mut self.index,
+            table => self.tables.entry(table).or_default(),
+        }
+    }
+
+    // the cache is shared by every table, so its keys are prefixed with the table id
+    fn cache_key(table: u32, key: &ByteStr) -> ByteString {
+        let mut cache_key = table.to_le_bytes().to_vec();
+        cache_key.extend_from_slice(key);
+        cache_key
+    }
+
+    /// Memory-maps the file as it currently is, so `get_mapped` can return values
+    /// without copying them. Records appended afterwards are read from the file
+    /// until `map` is called again.
+    ///
+    /// # Returns
+    ///
+    /// A Result that indicates whether the file could be mapped.
+    pub fn map(&mut self) -> Result<(), KvError> {
+        self.mapped = Some(MappedFile::new(&self.f)?);
+        Ok(())
+    }
+
+    /// Retrieves a value like `get`, but borrows it straight from the memory map when the
+    /// record lies in the mapped part of the file. Values of records written after the last
+    /// call to `map` (or all values, if the file was never mapped) are read from the file instead.
+    ///
+    /// The checksum is verified on every call; the value cache is not used.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to the key that the value is associated with.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(Some(Cow::Borrowed))` for mapped records, `Ok(Some(Cow::Owned))` for records read from
+    /// the file, and `Ok(None)` if the key does not exist.
+    pub fn get_mapped(&mut self, key: &ByteStr) -> Result<Option<Cow<'_, [u8]>>, KvError> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let with_table = self.has_tables();
+        let in_map = match &self.mapped {
+            Some(mapped) => mapped.contains_record(position, with_table),
+            None => false,
+        };
+
+        if in_map {
+            let mapped = self.mapped.as_ref().expect("checked above");
+            return Ok(Some(Cow::Borrowed(mapped.value_at(position, with_table)?)));
+        }
+
+        let kv = self.get_at(position)?; // the record was appended after the file was mapped
+
+        Ok(Some(Cow::Owned(kv.value)))
+    }
+
+    pub(crate) fn get_in(&mut self, table: u32, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        let position = match self.table_index(table).and_then(|index| index.get(key)) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let cache_key = self.cache.as_ref().map(|_| ActionKV::cache_key(table, key));
+
+        if let (Some(cache), Some(cache_key)) = (self.cache.as_mut(), cache_key.as_ref()) {
+            if let Some(value) = cache.get(cache_key) {
+                return Ok(Some(value));
+            }
+        }
+
         let kv = self.get_at(position)?;
 
+        if let (Some(cache), Some(cache_key)) = (self.cache.as_mut(), cache_key.as_ref()) {
+            cache.put(cache_key, &kv.value);
+        }
+
         Ok(Some(kv.value))
     }
 
@@ -176,35 +569,31 @@ impl ActionKV {
     ///
     /// # Returns
     ///
-    /// An `io::Result` containing an optional tuple of a `u64` representing the position of the
+    /// A `Result` containing an optional tuple of a `u64` representing the position of the
     /// record in the file and a `ByteString` representing the value of the record, if found. If the
     /// `target` key is not found, the result is `Ok(None)`.
     ///
     /// # Errors
     ///
-    /// This function returns an `Err` result if an IO error occurs during the search.
-    pub fn find(&mut self, target: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
+    /// This function returns an `Err` result if an IO error or corrupt record is encountered during the search.
+    pub fn find(&mut self, target: &ByteStr) -> Result<Option<(u64, ByteString)>, KvError> {
+        let with_table = self.has_tables();
         let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(self.data_start))?;
 
         let mut found: Option<(u64, ByteString)> = None;
 
         loop {
-            let position = f.seek(SeekFrom::Current(0))?;
+            let position = f.stream_position()?;
 
-            let maybe_kv = ActionKV::process_record(&mut f);
+            let maybe_kv = ActionKV::read_record(&mut f, position, with_table);
             let kv = match maybe_kv {
                 Ok(kv) => kv,
-                Err(err) => {
-                    match err.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            break;
-                        }
-                        _ => return Err(err)    
-                    }
-                }
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
             };
 
-            if kv.key == target {
+            if kv.table == DEFAULT_TABLE && kv.key == target {
                 found = Some((position, kv.value));
             }
 
@@ -216,6 +605,75 @@ impl ActionKV {
     }
     
 
+    /// Scans the whole file and summarizes how much of it is live data and how much
+    /// is garbage left behind by overwritten and deleted keys.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a `StoreStats` describing the file. Records are
+    /// classified against the in-memory index, so `load` should have been called first.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an `Err` result if an IO error or corrupt record is encountered during the scan.
+    pub fn stats(&mut self) -> Result<StoreStats, KvError> {
+        let mut stats = StoreStats {
+            file_size: self.file_len,
+            ..StoreStats::default()
+        };
+
+        // the catalog and the bookkeeping tables are counted apart from the user's keys
+        let internal: Vec<u32> = self.table_ids.iter()
+            .filter(|(name, _)| name.starts_with(INTERNAL_TABLE_PREFIX))
+            .map(|(_, id)| *id)
+            .chain(std::iter::once(CATALOG_TABLE))
+            .collect();
+
+        let with_table = self.has_tables();
+        let header_len = self.record_header_len();
+        let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(self.data_start))?;
+
+        loop {
+            let position = f.stream_position()?;
+
+            let maybe_kv = ActionKV::read_record(&mut f, position, with_table);
+            let kv = match maybe_kv {
+                Ok(kv) => kv,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err),
+            };
+
+            let record_len = header_len + kv.key.len() as u64 + kv.value.len() as u64;
+            stats.total_records += 1;
+
+            let index = match kv.table {
+                DEFAULT_TABLE => Some(&self.index),
+                table => self.tables.get(&table),
+            };
+
+            if index.and_then(|index| index.get(&kv.key)) != Some(&position) {
+                stats.dead_bytes += record_len;
+            } else if internal.contains(&kv.table) {
+                stats.internal_records += 1;
+                if kv.value.is_empty() {
+                    stats.reclaimable_bytes += record_len; // the catalog's tombstones of dropped tables
+                }
+            } else if kv.value.is_empty() {
+                stats.tombstones += 1;
+                stats.reclaimable_bytes += record_len;
+            } else {
+                stats.live_keys += 1;
+                stats.key_sizes.record(kv.key.len() as u64);
+                stats.value_sizes.record(kv.value.len() as u64);
+            }
+        }
+
+        stats.reclaimable_bytes += stats.dead_bytes;
+
+        Ok(stats)
+    }
+
     /// Inserts a key-value pair into a file, ignoring the index. 
     ///
     /// # Arguments
@@ -225,37 +683,195 @@ impl ActionKV {
     ///
     /// # Returns
     ///
-    /// An io::Result containing a u64 representing the current position of the cursor within the file if the
+    /// A Result containing a u64 representing the current position of the cursor within the file if the
     /// operation was successful.
-    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
-        let key_len = key.len();
-        let value_len = value.len();
-        let mut tmp = ByteString::with_capacity(key_len + value_len);
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::KeyTooLarge` or `KvError::ValueTooLarge` if either doesn't fit the
+    /// format's u32 length fields.
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> Result<u64, KvError> {
+        self.write_record(DEFAULT_TABLE, key, value)
+    }
 
-        for byte in key {
-            tmp.push(*byte);
-        }
+    fn write_record(&mut self, table: u32, key: &ByteStr, value: &ByteStr) -> Result<u64, KvError> {
+        self.throttle_writes();
 
-        for byte in value {
-            tmp.push(*byte);
-        }
+        let key_len = ActionKV::check_key_len(key)?;
+        let value_len = ActionKV::check_value_len(value.len() as u64)?;
+        self.check_table(table)?;
 
-        let checksum = crc32::checksum_ieee(&tmp);
+        let with_table = self.has_tables();
+        let record_len = self.record_header_len() + key.len() as u64 + value.len() as u64;
+        let mut f = BufWriter::new(&mut self.f);
+
+        // the checksum covers the table id, the key and the value, computed without copying them together
+        let seed = ActionKV::checksum_seed(table, with_table);
+        let checksum = crc32::update(crc32::update(seed, &crc32::IEEE_TABLE, key), &crc32::IEEE_TABLE, value);
 
         let next_byte = SeekFrom::End(0); // position of end of the file
-        let current_position = f.seek(SeekFrom::Current(0))?; // current position
-        f.seek(next_byte)?; // seek to end of the file
+        let current_position = f.seek(next_byte)?; // seek to end of the file, where the record will start
         
         // write bytes
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(value_len as u32)?;
-        f.write_all(&mut tmp)?;
+        ActionKV::write_record_header(&mut f, checksum, key_len, value_len, table, with_table)?;
+        f.write_all(key)?;
+        f.write_all(value)?;
+        f.flush()?;
+        self.file_len = current_position + record_len;
 
         Ok(current_position)
     }
 
+    fn write_record_header<W: Write>(f: &mut W, checksum: u32, key_len: u32, value_len: u32, table: u32, with_table: bool) -> io::Result<()> {
+        f.write_u32::<LittleEndian>(checksum)?;
+        f.write_u32::<LittleEndian>(key_len)?;
+        f.write_u32::<LittleEndian>(value_len)?;
+        if with_table {
+            f.write_u32::<LittleEndian>(table)?;
+        }
+        Ok(())
+    }
+
+    fn check_table(&self, table: u32) -> Result<(), KvError> {
+        if table != DEFAULT_TABLE && !self.has_tables() {
+            return Err(KvError::TablesUnsupported { version: self.version });
+        }
+        // ids are never reused, so a table without an index has been dropped
+        if table != DEFAULT_TABLE && table != CATALOG_TABLE && !self.tables.contains_key(&table) {
+            return Err(KvError::NoSuchTable { id: table });
+        }
+        Ok(())
+    }
+
+    fn check_key_len(key: &ByteStr) -> Result<u32, KvError> {
+        u32::try_from(key.len()).map_err(|_| KvError::KeyTooLarge { len: key.len() as u64 })
+    }
+
+    fn check_value_len(len: u64) -> Result<u32, KvError> {
+        u32::try_from(len).map_err(|_| KvError::ValueTooLarge { len })
+    }
+
+    /// Inserts a key and a value read from `value`, without holding the whole value in memory.
+    /// The checksum is computed while the value is copied to disk and written once it is complete.
+    ///
+    /// # Arguments
+    ///
+    /// * key - A reference to a ByteStr representing the key to insert into the file and index.
+    /// * value - A reader producing exactly `len` bytes of the value.
+    /// * len - The length of the value in bytes.
+    ///
+    /// # Returns
+    ///
+    /// A Result that indicates whether the operation was successful.
+    ///
+    /// # Errors
+    ///
+    /// If `value` fails or runs out before `len` bytes, the partial record is removed from
+    /// the file and the error is returned. A crash before the checksum is written leaves a
+    /// record with a placeholder checksum at the end of the file, which the next `load` removes.
+    pub fn insert_from_reader<R: Read>(&mut self, key: &ByteStr, value: R, len: u64) -> Result<(), KvError> {
+        let key_len = ActionKV::check_key_len(key)?;
+        let value_len = ActionKV::check_value_len(len)?;
+
+        let old = if self.has_secondary(DEFAULT_TABLE) {
+            self.get(key)?.filter(|old| !old.is_empty())
+        } else {
+            None
+        };
+
+        self.throttle_writes();
+
+        let position = self.f.seek(SeekFrom::End(0))?;
+        let with_table = self.has_tables();
+
+        let written = ActionKV::write_streamed(&mut self.f, position, key, key_len, value, value_len, with_table);
+        if let Err(err) = written {
+            self.f.set_len(position)?; // don't leave a half-written record for the next write to follow
+            self.file_len = position;
+            return Err(err);
+        }
+        self.file_len = position + self.record_header_len() + key_len as u64 + value_len as u64;
+
+        self.index_written(DEFAULT_TABLE, key, position)?;
+
+        if self.has_secondary(DEFAULT_TABLE) {
+            // extractors need the whole value, so indexed stores read it back
+            let new = self.get(key)?.unwrap_or_default();
+            self.update_secondary(key, old.as_deref(), &new)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_streamed<R: Read>(file: &mut File, position: u64, key: &ByteStr, key_len: u32, value: R, value_len: u32, with_table: bool) -> Result<(), KvError> {
+        let mut f = BufWriter::new(file);
+
+        // the checksum is a placeholder until the value has been read
+        ActionKV::write_record_header(&mut f, STREAMING_CHECKSUM, key_len, value_len, DEFAULT_TABLE, with_table)?;
+        f.write_all(key)?;
+
+        let seed = ActionKV::checksum_seed(DEFAULT_TABLE, with_table);
+        let mut checksum = crc32::update(seed, &crc32::IEEE_TABLE, key);
+        let mut value = value.take(value_len as u64);
+        let mut buf = [0u8; 64 * 1024];
+        let mut remaining = value_len as u64;
+
+        while remaining > 0 {
+            let n = value.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            checksum = crc32::update(checksum, &crc32::IEEE_TABLE, &buf[..n]);
+            f.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+
+        f.seek(SeekFrom::Start(position))?; // flushes the buffered value first
+        f.write_u32::<LittleEndian>(checksum)?;
+        f.flush()?;
+
+        Ok(())
+    }
+
+    /// Opens the value stored under `key` for reading, without loading it into memory.
+    /// The value's checksum is verified incrementally as it is read.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to the key that the value is associated with.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(Some(ValueReader))` positioned at the first byte of the value if the key exists,
+    /// `Ok(None)` otherwise. Reading past the last byte of a corrupt value fails with an
+    /// `InvalidData` error that wraps `KvError::Corruption`.
+    pub fn get_reader(&mut self, key: &ByteStr) -> Result<Option<ValueReader<'_>>, KvError> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+
+        let with_table = self.has_tables();
+        let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(position))?;
+
+        let saved_checksum = f.read_u32::<LittleEndian>()?;
+        let key_len = f.read_u32::<LittleEndian>()?;
+        let val_len = f.read_u32::<LittleEndian>()?;
+        let table = if with_table { f.read_u32::<LittleEndian>()? } else { DEFAULT_TABLE };
+
+        let mut stored_key = ByteString::with_capacity((key_len as u64).min(MAX_PREALLOCATION) as usize);
+        f.by_ref().take(key_len as u64).read_to_end(&mut stored_key)?;
+        if stored_key.len() != key_len as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let seed = ActionKV::checksum_seed(table, with_table);
+        let checksum = crc32::update(seed, &crc32::IEEE_TABLE, &stored_key);
+
+        Ok(Some(ValueReader::new(f, position, saved_checksum, checksum, val_len as u64)))
+    }
+
     /// Inserts a key-value pair into a file and creates an index for the key in the Hashmap. 
     ///
     /// # Arguments
@@ -265,15 +881,54 @@ impl ActionKV {
     ///
     /// # Returns
     ///
-    /// An io::Result that indicates whether the operation was successful.
-    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
-        let position = self.insert_but_ignore_index(key, value)?;
+    /// A Result that indicates whether the operation was successful.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        self.insert_in(DEFAULT_TABLE, key, value)
+    }
 
-        self.index.insert(key.to_vec(), position); // key.to_vec() converts the &ByteStr to a ByteString
+    pub(crate) fn insert_in(&mut self, table: u32, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        let old = if self.has_secondary(table) {
+            self.get_in(table, key)?.filter(|old| !old.is_empty())
+        } else {
+            None
+        };
+
+        let position = self.write_record(table, key, value)?;
+
+        self.index_written(table, key, position)?;
+
+        if self.has_secondary(table) {
+            self.update_secondary(key, old.as_deref(), value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Points the index at a record that was just written and drops any cached copy of the old value.
+    fn index_written(&mut self, table: u32, key: &ByteStr, position: u64) -> Result<(), KvError> {
+        let replaced = self.table_index_mut(table).insert(key.to_vec(), position); // key.to_vec() converts the &ByteStr to a ByteString
+
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(&ActionKV::cache_key(table, key));
+        }
+
+        if let Some(replaced) = replaced {
+            self.dead_bytes += self.record_len_at(replaced)?;
+            self.dead_records += 1;
+        }
 
         Ok(())
     }
 
+    /// Length of the record at `position`, header included, read from its length fields.
+    fn record_len_at(&mut self, position: u64) -> Result<u64, KvError> {
+        self.f.seek(SeekFrom::Start(position + 4))?; // skip the checksum
+        let key_len = self.f.read_u32::<LittleEndian>()? as u64;
+        let val_len = self.f.read_u32::<LittleEndian>()? as u64;
+
+        Ok(self.record_header_len() + key_len + val_len)
+    }
+
     /// Updates the value of an existing key in the `HashMap`, or inserts a new key-value
     /// pair if the key does not already exist.
     ///
@@ -288,10 +943,10 @@ impl ActionKV {
     /// # Errors
     ///
     /// If the underlying `HashMap` is unable to allocate memory or write to the disk, this
-    /// function will return an `io::Result` indicating the specific error encountered.
+    /// function will return a `KvError` indicating the specific error encountered.
     ///
     #[inline]
-    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
         self.insert(key, value)
     }
 
@@ -304,10 +959,155 @@ impl ActionKV {
     /// # Errors
     ///
     /// If the underlying `HashMap` is unable to allocate memory or write to the disk, this
-    /// function will return an `io::Result` indicating the specific error encountered.
+    /// function will return a `KvError` indicating the specific error encountered.
     ///
     #[inline]
-    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+    pub fn delete(&mut self, key: &ByteStr) -> Result<(), KvError> {
         self.insert(key, b"")
     }
+
+    /// Returns the value of `key`, treating a tombstone left by `delete` as absent.
+    fn current_value(&mut self, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        Ok(self.get(key)?.filter(|value| !value.is_empty()))
+    }
+
+    /// Writes `new` under `key` only if the key currently holds `expected`.
+    ///
+    /// The check and the write happen under the same `&mut` borrow, and `open` holds an
+    /// exclusive lock on the file, so wrapping the store in a `Mutex` is enough to make
+    /// this atomic with respect to every other writer.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A reference to a `ByteStr` representing the key to write.
+    /// * `expected` - The value the key must hold, or `None` if it must be absent (or deleted).
+    /// * `new` - A reference to a `ByteStr` representing the value to write.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Conflict` holding the current value if it isn't `expected`.
+    pub fn compare_and_swap(&mut self, key: &ByteStr, expected: Option<&ByteStr>, new: &ByteStr) -> Result<(), KvError> {
+        let current = self.current_value(key)?;
+        if current.as_deref() != expected {
+            return Err(KvError::Conflict { current });
+        }
+
+        self.insert(key, new)
+    }
+
+    /// Inserts a key-value pair only if the key is absent (or has been deleted).
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Conflict` holding the current value if the key already exists.
+    #[inline]
+    pub fn insert_if_absent(&mut self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    /// Deletes a key only if it currently holds `expected`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::Conflict` holding the current value if it isn't `expected`.
+    pub fn delete_if_equals(&mut self, key: &ByteStr, expected: &ByteStr) -> Result<(), KvError> {
+        let current = self.current_value(key)?;
+        if current.as_deref() != Some(expected) {
+            return Err(KvError::Conflict { current });
+        }
+
+        self.delete(key)
+    }
+
+    /// Returns a handle to the table called `name`, creating it if it doesn't exist yet.
+    /// Every table has its own keys, so the same key can hold different values in different tables.
+    ///
+    /// Names starting with `+` are for bookkeeping, such as an index kept by the application:
+    /// those tables are left out of `list_tables` and of the key counts of `stats`. Names
+    /// starting with `+index:` are reserved for the entries of secondary indexes.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the table.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::ReservedTableName` for names starting with `+index:`, and
+    /// `KvError::TablesUnsupported` if the table doesn't exist and the file was written
+    /// by a format version without tables.
+    pub fn open_table(&mut self, name: &str) -> Result<Table<'_>, KvError> {
+        ActionKV::check_table_name(name)?;
+        let id = self.table_id(name)?;
+
+        Ok(Table::new(self, name, id))
+    }
+
+    /// The id of the table called `name`, which is created if it doesn't exist yet.
+    pub(crate) fn table_id(&mut self, name: &str) -> Result<u32, KvError> {
+        if let Some(id) = self.table_ids.get(name) {
+            return Ok(*id);
+        }
+
+        let id = self.next_table_id;
+        self.insert_in(CATALOG_TABLE, name.as_bytes(), &id.to_le_bytes())?;
+
+        self.next_table_id += 1;
+        self.table_ids.insert(name.to_string(), id);
+        self.tables.insert(id, HashMap::new());
+        Ok(id)
+    }
+
+    fn check_table_name(name: &str) -> Result<(), KvError> {
+        if name.starts_with(INDEX_TABLE_PREFIX) {
+            return Err(KvError::ReservedTableName { name: name.to_string() });
+        }
+        Ok(())
+    }
+
+    /// True if a table called `name` exists, including the ones `list_tables` leaves out.
+    pub fn has_table(&self, name: &str) -> bool {
+        self.table_ids.contains_key(name)
+    }
+
+    /// Returns the names of every table in the store, sorted. The default table and the
+    /// bookkeeping tables, whose names start with `+`, aren't included.
+    pub fn list_tables(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.table_ids.keys()
+            .filter(|name| !name.starts_with(INTERNAL_TABLE_PREFIX))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Deletes the table called `name` and every key in it. Its records are left
+    /// in the file as garbage.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if the table existed, `Ok(false)` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::ReservedTableName` for the tables of secondary indexes, whose names start with `+index:`.
+    pub fn drop_table(&mut self, name: &str) -> Result<bool, KvError> {
+        ActionKV::check_table_name(name)?;
+
+        let id = match self.table_ids.get(name) {
+            None => return Ok(false),
+            Some(id) => *id,
+        };
+
+        self.insert_in(CATALOG_TABLE, name.as_bytes(), b"")?;
+
+        self.table_ids.remove(name);
+        if let Some(index) = self.tables.remove(&id) {
+            for position in index.into_values() {
+                self.dead_bytes += self.record_len_at(position)?;
+                self.dead_records += 1;
+            }
+        }
+
+        Ok(true)
+    }
 }
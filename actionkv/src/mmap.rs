@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use memmap2::{Mmap, MmapOptions};
+
+use crate::{ActionKV, KvError, DEFAULT_TABLE};
+
+const TORN_RECORD_WINDOW: u64 = 64 * 1024; // how far after a short record's header its successor is looked for
+
+/// A read-only memory map of the part of the store file that existed when it was mapped.
+///
+/// The log is append-only, so bytes below `len` never change while the store is open and
+/// records in that range can be handed out as slices of the map. Records appended later
+/// are outside the map until it is refreshed with `ActionKV::map`.
+#[derive(Debug)]
+pub(crate) struct MappedFile {
+    map: Mmap,
+}
+
+impl MappedFile {
+    pub(crate) fn new(f: &File) -> Result<Self, KvError> {
+        let len = f.metadata()?.len();
+        // safety: ActionKV holds an exclusive lock on the file and never rewrites or
+        // truncates bytes that are already part of a record, so the mapped range is stable
+        let map = unsafe { MmapOptions::new().len(len as usize).map(f)? };
+        Ok(MappedFile { map })
+    }
+
+    /// True if the record at `position` lies entirely inside the mapped range. Only the
+    /// length fields are read; records appended after the file was mapped don't fit.
+    pub(crate) fn contains_record(&self, position: u64, with_table: bool) -> bool {
+        match self.lengths(position, with_table) {
+            None => false,
+            Some((header_len, key_len, val_len)) => {
+                position + header_len + key_len + val_len <= self.map.len() as u64
+            }
+        }
+    }
+
+    fn lengths(&self, position: u64, with_table: bool) -> Option<(u64, u64, u64)> {
+        let header_len: u64 = if with_table { 16 } else { 12 };
+        if position + header_len > self.map.len() as u64 {
+            return None;
+        }
+
+        let start = position as usize;
+        let key_len = LittleEndian::read_u32(&self.map[start + 4..start + 8]) as u64;
+        let val_len = LittleEndian::read_u32(&self.map[start + 8..start + 12]) as u64;
+
+        Some((header_len, key_len, val_len))
+    }
+
+    /// Checks that the record at `position`, which runs past the end of the file, is the last
+    /// one: a write cut short by a crash. If a valid record starts among the bytes after its
+    /// header and is followed by valid records up to the end of the file (or a torn one),
+    /// it's the length fields that are corrupt, and trimming the record would throw those
+    /// records away.
+    ///
+    /// Only records starting within `TORN_RECORD_WINDOW` bytes of the header are tried, and
+    /// at most twice the bytes after `position` are checksummed in all, so a large torn value
+    /// doesn't take time quadratic in its size. Corrupt lengths that hide more than that are
+    /// taken for a torn record.
+    pub(crate) fn check_torn_record(&self, position: u64, with_table: bool) -> Result<(), KvError> {
+        let header_len: u64 = if with_table { 16 } else { 12 };
+        let len = self.map.len() as u64;
+        let mut follows_to_end: HashMap<u64, bool> = HashMap::new();
+        let mut budget = 2 * (len - position);
+
+        for start in position + header_len..len.min(position + header_len + TORN_RECORD_WINDOW) {
+            // a key is required, as a run of zeros reads as empty records in files without tables
+            match self.lengths(start, with_table) {
+                Some((_, 0, _)) | None => continue,
+                Some((header_len, key_len, val_len)) if start + header_len + key_len + val_len > len => continue,
+                Some(_) => {}
+            }
+
+            match self.follows_to_end(start, with_table, &mut follows_to_end, &mut budget) {
+                None => return Ok(()), // out of budget
+                Some(false) => continue,
+                Some(true) => {
+                    let start = position as usize;
+                    let saved_checksum = LittleEndian::read_u32(&self.map[start..start + 4]);
+                    let table = if with_table { LittleEndian::read_u32(&self.map[start + 12..start + 16]) } else { DEFAULT_TABLE };
+                    let seed = ActionKV::checksum_seed(table, with_table);
+                    let checksum = crc32::update(seed, &crc32::IEEE_TABLE, &self.map[start + header_len as usize..]);
+                    return Err(KvError::Corruption { offset: position, expected: saved_checksum, actual: checksum });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// True if valid records run from `position` to the end of the map, the last of them
+    /// possibly cut short. Results are remembered in `known` for every record passed, so
+    /// that chains which join up are only followed once. Checksummed bytes are taken from
+    /// `budget`; `None` means it ran out before the answer was known.
+    fn follows_to_end(&self, mut position: u64, with_table: bool, known: &mut HashMap<u64, bool>, budget: &mut u64) -> Option<bool> {
+        let len = self.map.len() as u64;
+        let mut passed = vec![];
+
+        let follows = loop {
+            if let Some(&follows) = known.get(&position) {
+                break follows;
+            }
+            let (end, data_len) = match self.lengths(position, with_table) {
+                None => break true, // the end of the file, or a torn header
+                Some((header_len, key_len, val_len)) => (position + header_len + key_len + val_len, key_len + val_len),
+            };
+            if end > len {
+                break true;
+            }
+            *budget = budget.checked_sub(data_len)?;
+            if self.value_at(position, with_table).is_err() {
+                break false;
+            }
+            passed.push(position);
+            position = end;
+        };
+
+        for position in passed {
+            known.insert(position, follows);
+        }
+        Some(follows)
+    }
+
+    /// Returns the value of the record at `position`, after verifying its checksum.
+    /// The record must be inside the mapped range, see `contains_record`.
+    pub(crate) fn value_at(&self, position: u64, with_table: bool) -> Result<&[u8], KvError> {
+        let (header_len, key_len, val_len) = self.lengths(position, with_table).expect("record outside the map");
+
+        let start = position as usize;
+        let saved_checksum = LittleEndian::read_u32(&self.map[start..start + 4]);
+        let table = if with_table { LittleEndian::read_u32(&self.map[start + 12..start + 16]) } else { DEFAULT_TABLE };
+
+        let data_start = start + header_len as usize;
+        let data = &self.map[data_start..data_start + (key_len + val_len) as usize];
+
+        let seed = ActionKV::checksum_seed(table, with_table);
+        let checksum = crc32::update(seed, &crc32::IEEE_TABLE, data);
+        if checksum != saved_checksum {
+            return Err(KvError::Corruption { offset: position, expected: saved_checksum, actual: checksum });
+        }
+
+        Ok(&data[key_len as usize..])
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::{ActionKV, KvError, DEFAULT_TABLE};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// Table names with this prefix hold the entries of secondary indexes.
+pub(crate) const INDEX_TABLE_PREFIX: &str = "+index:";
+
+type Extractor = Box<dyn Fn(&ByteStr) -> Option<ByteString> + Send>;
+
+/// A secondary index over the values of the default table. Maps the field returned
+/// by the extractor to every primary key whose value produced it.
+pub(crate) struct SecondaryIndex {
+    table: u32, // where the entries are persisted
+    extractor: Extractor,
+    entries: HashMap<ByteString, BTreeSet<ByteString>>,
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("table", &self.table)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+// entries are stored as keys of the index table: u32 field length, field, primary key
+fn encode_entry(field: &ByteStr, key: &ByteStr) -> ByteString {
+    let mut entry = Vec::with_capacity(4 + field.len() + key.len());
+    entry.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    entry.extend_from_slice(field);
+    entry.extend_from_slice(key);
+    entry
+}
+
+fn decode_entry(entry: &ByteStr) -> Option<(&ByteStr, &ByteStr)> {
+    let field_len = u32::from_le_bytes(entry.get(..4)?.try_into().ok()?) as usize;
+    let field = entry.get(4..4 + field_len)?;
+    let key = &entry[4 + field_len..];
+    Some((field, key))
+}
+
+impl ActionKV {
+    /// Registers a secondary index called `name` over the values of the default table.
+    /// `extractor` returns the field to index a value by, or `None` to leave it out.
+    ///
+    /// The index entries are stored in the file. If the index was registered before,
+    /// they are loaded from there; otherwise the index is built from every live key.
+    /// Either way, it is then kept up to date by every insert, update and delete.
+    ///
+    /// Extractors aren't stored, so every process that writes to the store must register
+    /// the same indexes after `load`, or the stored entries go stale.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name used to query the index with `get_by_index`.
+    /// * `extractor` - A function from a value to the field it should be indexed by.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::TablesUnsupported` for files written by a format version without tables.
+    pub fn register_index<F>(&mut self, name: &str, extractor: F) -> Result<(), KvError>
+    where
+        F: Fn(&ByteStr) -> Option<ByteString> + Send + 'static,
+    {
+        let table_name = format!("{}{}", INDEX_TABLE_PREFIX, name);
+        let existed = self.table_ids.contains_key(&table_name);
+        let table = self.table_id(&table_name)?;
+
+        let mut index = SecondaryIndex {
+            table,
+            extractor: Box::new(extractor),
+            entries: HashMap::new(),
+        };
+
+        if existed {
+            let stored: Vec<ByteString> = self.table_index(table).map(|i| i.keys().cloned().collect()).unwrap_or_default();
+            for entry in stored {
+                if self.get_in(table, &entry)?.is_some_and(|marker| !marker.is_empty()) {
+                    if let Some((field, key)) = decode_entry(&entry) {
+                        index.entries.entry(field.to_vec()).or_default().insert(key.to_vec());
+                    }
+                }
+            }
+        } else {
+            let keys: Vec<ByteString> = self.index.keys().cloned().collect();
+            for key in keys {
+                let value = match self.get(&key)? {
+                    Some(value) if !value.is_empty() => value,
+                    _ => continue,
+                };
+                if let Some(field) = (index.extractor)(&value) {
+                    self.insert_in(table, &encode_entry(&field, &key), b"\x01")?;
+                    index.entries.entry(field).or_default().insert(key);
+                }
+            }
+        }
+
+        self.secondary.insert(name.to_string(), index);
+
+        Ok(())
+    }
+
+    /// Returns the key-value pairs of the default table whose value is indexed by
+    /// `field` in the secondary index called `name`, ordered by key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvError::NoSuchIndex` if no index called `name` has been registered.
+    pub fn get_by_index(&mut self, name: &str, field: &ByteStr) -> Result<Vec<(ByteString, ByteString)>, KvError> {
+        let keys: Vec<ByteString> = match self.secondary.get(name) {
+            None => return Err(KvError::NoSuchIndex { name: name.to_string() }),
+            Some(index) => index.entries.get(field).map(|keys| keys.iter().cloned().collect()).unwrap_or_default(),
+        };
+
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                found.push((key, value));
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Brings every secondary index in line with `key` changing from `old` to `new`.
+    /// An empty `new` value is a delete.
+    pub(crate) fn update_secondary(&mut self, key: &ByteStr, old: Option<&ByteStr>, new: &ByteStr) -> Result<(), KvError> {
+        let mut writes = Vec::new();
+
+        for index in self.secondary.values_mut() {
+            let old_field = old.and_then(|value| (index.extractor)(value));
+            let new_field = if new.is_empty() { None } else { (index.extractor)(new) };
+            if old_field == new_field {
+                continue;
+            }
+
+            if let Some(field) = old_field {
+                if let Some(keys) = index.entries.get_mut(&field) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        index.entries.remove(&field);
+                    }
+                }
+                writes.push((index.table, encode_entry(&field, key), &b""[..]));
+            }
+
+            if let Some(field) = new_field {
+                writes.push((index.table, encode_entry(&field, key), &b"\x01"[..]));
+                index.entries.entry(field).or_default().insert(key.to_vec());
+            }
+        }
+
+        for (table, entry, marker) in writes {
+            self.insert_in(table, &entry, marker)?;
+        }
+
+        Ok(())
+    }
+
+    /// True if any secondary index has to see writes to `table`.
+    pub(crate) fn has_secondary(&self, table: u32) -> bool {
+        table == DEFAULT_TABLE && !self.secondary.is_empty()
+    }
+}
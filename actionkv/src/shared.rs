@@ -0,0 +1,76 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crate::transaction::{run_shared, Transaction};
+use crate::{ActionKV, KvError};
+
+/// A cloneable handle to one `ActionKV`, for stores used by several threads at once.
+///
+/// Every method of the store is reached through `lock`, so single operations, including
+/// the conditional writes, are atomic with respect to every other clone of the handle.
+#[derive(Debug, Clone)]
+pub struct SharedActionKV {
+    inner: Arc<Mutex<ActionKV>>,
+}
+
+/// Exclusive access to a `SharedActionKV`, as returned by `lock`.
+///
+/// Writes throttled by a background `Compactor` are slowed down when the guard is dropped,
+/// after the lock has been released, so that only the writer waits and every other
+/// user of the store, the compactor included, can go on meanwhile.
+#[derive(Debug)]
+pub struct StoreGuard<'a> {
+    guard: Option<MutexGuard<'a, ActionKV>>, // only None while being dropped
+}
+
+impl SharedActionKV {
+    pub fn new(store: ActionKV) -> Self {
+        SharedActionKV { inner: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Gives exclusive access to the store until the guard is dropped.
+    pub fn lock(&self) -> StoreGuard<'_> {
+        StoreGuard { guard: Some(self.inner.lock().expect("ActionKV mutex poisoned")) }
+    }
+
+    /// Runs `f` as an optimistic transaction over the default table.
+    ///
+    /// `f` runs without holding the lock. When it returns `Ok`, the lock is taken and the
+    /// transaction commits only if none of the keys it read has been written since; otherwise
+    /// `f` is run again, up to `TRANSACTION_ATTEMPTS` times in all.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`, or `KvError::TransactionConflict` if every attempt conflicted.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut Transaction) -> Result<T, KvError>,
+    {
+        run_shared(self, f)
+    }
+}
+
+impl Deref for StoreGuard<'_> {
+    type Target = ActionKV;
+
+    fn deref(&self) -> &ActionKV {
+        self.guard.as_ref().expect("guard is only taken on drop")
+    }
+}
+
+impl DerefMut for StoreGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ActionKV {
+        self.guard.as_mut().expect("guard is only taken on drop")
+    }
+}
+
+impl Drop for StoreGuard<'_> {
+    fn drop(&mut self) {
+        let delay = self.guard.as_mut().and_then(|store| store.take_write_delay());
+        drop(self.guard.take()); // unlocks the store before waiting
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+    }
+}
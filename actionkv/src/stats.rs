@@ -0,0 +1,58 @@
+use serde_derive::Serialize;
+
+/// One bucket of a `SizeHistogram`, counting the sizes that are `<= upper_bound`
+/// and larger than the previous bucket's bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Bucket {
+    pub upper_bound: u64,
+    pub count: u64,
+}
+
+/// Distribution of key or value sizes, bucketed by powers of two.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeHistogram {
+    pub buckets: Vec<Bucket>,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: u64) {
+        // bucket 0 holds empty keys/values, bucket i holds sizes in (2^(i-1), 2^i]
+        let i = match size {
+            0 => 0,
+            n => (64 - (n - 1).leading_zeros()) as usize + 1,
+        };
+
+        while self.buckets.len() <= i {
+            let upper_bound = match self.buckets.len() {
+                0 => 0,
+                n => 1u64 << (n - 1),
+            };
+            self.buckets.push(Bucket { upper_bound, count: 0 });
+        }
+
+        self.buckets[i].count += 1;
+    }
+}
+
+/// A summary of how a store file is laid out on disk, as returned by `ActionKV::stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// Keys whose latest record holds a value, in the default table and the user's tables.
+    pub live_keys: u64,
+    /// Every record in the file, including overwritten ones and tombstones.
+    pub total_records: u64,
+    /// Keys whose latest record is a tombstone (an empty value written by `delete`).
+    pub tombstones: u64,
+    /// Latest records of the bookkeeping tables (the table catalog, secondary indexes and
+    /// tables whose names start with `+`), which the counts of keys leave out.
+    pub internal_records: u64,
+    /// Bytes taken by records that have been overwritten by a later record for the same key.
+    pub dead_bytes: u64,
+    pub file_size: u64,
+    /// Bytes that compacting the file would free: dead records plus tombstones.
+    pub reclaimable_bytes: u64,
+    /// Sizes of the keys of live records.
+    pub key_sizes: SizeHistogram,
+    /// Sizes of the values of live records.
+    pub value_sizes: SizeHistogram,
+}
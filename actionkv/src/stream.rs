@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use crc::crc32;
+
+use crate::KvError;
+
+/// A `Read` over the value bytes of a single record, as returned by `ActionKV::get_reader`.
+///
+/// The checksum is computed as the value streams past. Once the last byte has been
+/// read, a mismatch is reported as an `InvalidData` I/O error wrapping `KvError::Corruption`.
+#[derive(Debug)]
+pub struct ValueReader<'a> {
+    inner: io::Take<BufReader<&'a mut File>>,
+    offset: u64,     // start of the record, for error reporting
+    expected: u32,   // checksum saved in the record header
+    checksum: u32,   // running checksum of the key and the value bytes read so far
+    len: u64,
+    verified: bool,
+}
+
+impl<'a> ValueReader<'a> {
+    /// `inner` must be positioned at the start of the value. `checksum` covers the key,
+    /// which has already been read.
+    pub(crate) fn new(inner: BufReader<&'a mut File>, offset: u64, expected: u32, checksum: u32, len: u64) -> Self {
+        ValueReader {
+            inner: inner.take(len),
+            offset,
+            expected,
+            checksum,
+            len,
+            verified: false,
+        }
+    }
+
+    /// Total length of the value in bytes, regardless of how much has been read.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum = crc32::update(self.checksum, &crc32::IEEE_TABLE, &buf[..n]);
+
+        if self.inner.limit() > 0 {
+            if n == 0 && !buf.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into()); // file is shorter than the record claims
+            }
+            return Ok(n);
+        }
+
+        if !self.verified {
+            self.verified = true;
+            if self.checksum != self.expected {
+                let err = KvError::Corruption { offset: self.offset, expected: self.expected, actual: self.checksum };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+
+        Ok(n)
+    }
+}
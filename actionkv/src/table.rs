@@ -0,0 +1,49 @@
+use crate::{ActionKV, KvError};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// A named table inside an `ActionKV` store, as returned by `ActionKV::open_table`.
+///
+/// Keys in a table never collide with keys in the default table or in other tables.
+#[derive(Debug)]
+pub struct Table<'a> {
+    store: &'a mut ActionKV,
+    name: String,
+    id: u32,
+}
+
+impl<'a> Table<'a> {
+    pub(crate) fn new(store: &'a mut ActionKV, name: &str, id: u32) -> Self {
+        Table { store, name: name.to_string(), id }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Retrieves the value stored under `key` in this table, like `ActionKV::get`.
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        self.store.get_in(self.id, key)
+    }
+
+    /// Inserts a key-value pair into this table, like `ActionKV::insert`.
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        self.store.insert_in(self.id, key, value)
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<(), KvError> {
+        self.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &ByteStr) -> Result<(), KvError> {
+        self.insert(key, b"")
+    }
+
+    /// Returns every key in this table, including deleted ones, which hold an empty value.
+    pub fn keys(&self) -> Vec<ByteString> {
+        self.store.table_index(self.id).map(|index| index.keys().cloned().collect()).unwrap_or_default()
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{ActionKV, KvError, SharedActionKV};
+
+type ByteString = Vec<u8>;
+type ByteStr = [u8];
+
+/// How many times `transaction` runs the closure before giving up on conflicts.
+pub const TRANSACTION_ATTEMPTS: u32 = 10;
+
+#[derive(Debug)]
+enum Source<'a> {
+    Owned(&'a mut ActionKV),
+    Shared(&'a SharedActionKV),
+}
+
+/// A read-modify-write transaction over the default table, as passed to the closure
+/// given to `ActionKV::transaction` or `SharedActionKV::transaction`.
+///
+/// The first read of each key is remembered, so repeated reads see the same value
+/// and writes buffered by the transaction itself. Writes go to the store only when
+/// the closure returns `Ok`, and only if none of the keys read has been written in the meantime.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    source: Source<'a>,
+    generation: u64, // of the store when the transaction began
+    reads: HashMap<ByteString, (Option<u64>, Option<ByteString>)>, // position and value when first read
+    writes: BTreeMap<ByteString, ByteString>, // an empty value is a delete
+}
+
+impl<'a> Transaction<'a> {
+    fn new(source: Source<'a>) -> Self {
+        let generation = match &source {
+            Source::Owned(store) => store.generation,
+            Source::Shared(store) => store.lock().generation,
+        };
+        Transaction { source, generation, reads: HashMap::new(), writes: BTreeMap::new() }
+    }
+
+    /// Reads `key`, seeing this transaction's own writes. A deleted key reads as `None`.
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>, KvError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(Some(value.clone()).filter(|value| !value.is_empty()));
+        }
+
+        if let Some((_, value)) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+
+        let (position, value) = match &mut self.source {
+            Source::Owned(store) => read_committed(store, key)?,
+            Source::Shared(store) => read_committed(&mut store.lock(), key)?,
+        };
+        self.reads.insert(key.to_vec(), (position, value.clone()));
+
+        Ok(value)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.writes.insert(key.to_vec(), value.to_vec());
+    }
+
+    #[inline]
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.insert(key, value)
+    }
+
+    #[inline]
+    pub fn delete(&mut self, key: &ByteStr) {
+        self.insert(key, b"")
+    }
+
+    /// Checks that nothing this transaction read has changed, then applies its writes.
+    ///
+    /// A compaction since the transaction began moves every record, and can put a key
+    /// that was written in the meantime back at the position it was read from, so any
+    /// compaction counts as a change to everything read.
+    ///
+    /// The writes are appended one record at a time, so a crash halfway through a
+    /// commit can leave only some of them in the file.
+    fn commit(self) -> Result<(), KvError> {
+        let Transaction { source, generation, reads, writes } = self;
+
+        let mut guard;
+        let store: &mut ActionKV = match source {
+            Source::Owned(store) => store,
+            Source::Shared(store) => {
+                guard = store.lock();
+                &mut guard
+            }
+        };
+
+        let compacted = store.generation != generation;
+        for (key, (position, _)) in reads {
+            if compacted || store.index.get(&key).copied() != position {
+                return Err(KvError::TransactionConflict { key });
+            }
+        }
+
+        for (key, value) in writes {
+            store.insert(&key, &value)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_committed(store: &mut ActionKV, key: &ByteStr) -> Result<(Option<u64>, Option<ByteString>), KvError> {
+    let position = store.index.get(key).copied();
+    let value = store.get(key)?.filter(|value| !value.is_empty());
+    Ok((position, value))
+}
+
+impl ActionKV {
+    /// Runs `f` as a transaction over the default table. The writes it makes are applied
+    /// together once it returns `Ok`; if it returns `Err`, nothing is written.
+    ///
+    /// With exclusive access to the store nothing can change underneath the transaction,
+    /// so it always commits on the first attempt. Use `SharedActionKV::transaction` when
+    /// several threads write to the same store.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`, or the first error hit while committing.
+    pub fn transaction<T, F>(&mut self, mut f: F) -> Result<T, KvError>
+    where
+        F: FnMut(&mut Transaction) -> Result<T, KvError>,
+    {
+        let mut tx = Transaction::new(Source::Owned(self));
+        let result = f(&mut tx)?;
+        tx.commit()?;
+
+        Ok(result)
+    }
+}
+
+/// Runs `f` in a fresh transaction until it commits, `f` fails, or the attempts run out.
+pub(crate) fn run_shared<T, F>(store: &SharedActionKV, mut f: F) -> Result<T, KvError>
+where
+    F: FnMut(&mut Transaction) -> Result<T, KvError>,
+{
+    let mut attempt = 1;
+    loop {
+        let mut tx = Transaction::new(Source::Shared(store));
+        let result = f(&mut tx)?;
+
+        match tx.commit() {
+            Err(KvError::TransactionConflict { .. }) if attempt < TRANSACTION_ATTEMPTS => attempt += 1,
+            Err(err) => return Err(err),
+            Ok(()) => return Ok(result),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use libactionkv::ActionKV;
+
+/// A record as the first versions of ActionKV wrote it: no table id, and a checksum
+/// over the key and the value alone.
+fn baseline_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut data = key.to_vec();
+    data.extend_from_slice(value);
+
+    let mut record = Vec::new();
+    record.write_u32::<LittleEndian>(crc::crc32::checksum_ieee(&data)).unwrap();
+    record.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+    record.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+    record.extend_from_slice(&data);
+    record
+}
+
+fn akv_disk(path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_akv_disk")).arg(path).args(args).output().unwrap()
+}
+
+fn get(path: &Path, key: &str) -> String {
+    let output = akv_disk(path, &["get", key]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn reads_and_writes_files_of_the_baseline_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    // what `akv_disk store.akv insert a apple` left behind before files had a header
+    let mut file = baseline_record(b"a", b"apple");
+    let index: HashMap<Vec<u8>, u64> = HashMap::from([(b"a".to_vec(), 0)]);
+    file.extend(baseline_record(b"+index", &bincode::serialize(&index).unwrap()));
+    std::fs::write(&path, &file).unwrap();
+
+    assert_eq!(get(&path, "a"), format!("{:?}\n", b"apple"));
+
+    let output = akv_disk(&path, &["insert", "b", "banana"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(get(&path, "b"), format!("{:?}\n", b"banana"));
+    assert_eq!(get(&path, "a"), format!("{:?}\n", b"apple"));
+
+    // still in the baseline format, so older builds can read it too
+    let contents = std::fs::read(&path).unwrap();
+    assert!(contents.starts_with(&file));
+    assert!(contents[file.len()..].starts_with(&baseline_record(b"b", b"banana")));
+}
+
+#[test]
+fn the_index_is_not_counted_as_a_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    for (key, value) in [("a", "apple"), ("b", "banana")] {
+        let output = akv_disk(&path, &["insert", key, value]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    assert_eq!(get(&path, "b"), format!("{:?}\n", b"banana"));
+
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!((stats.live_keys, stats.tombstones), (2, 0));
+    assert_eq!(stats.internal_records, 2); // the catalog entry of the index table and the index
+    assert!(store.list_tables().is_empty());
+}
@@ -0,0 +1,27 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn akv_mem(path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_akv_mem")).arg(path).args(args).output().unwrap()
+}
+
+#[test]
+fn invalid_dump_options_are_usage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    for args in [
+        &["export", "--format", "xml"][..],
+        &["export", "--encoding", "rot13"],
+        &["import", "--format"],
+    ] {
+        let output = akv_mem(&path, args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("Usage:"), "{:?}: {}", args, stderr);
+        assert!(!stderr.contains("panicked"), "{:?}: {}", args, stderr);
+    }
+
+    let output = akv_mem(&path, &["export", "--format", "xml"]);
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("unknown format \"xml\", expected jsonl, csv or sql"));
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "async")]
+
+use std::time::Duration;
+
+use libactionkv::AsyncActionKV;
+
+#[tokio::test]
+async fn operations_run_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    {
+        let store = AsyncActionKV::open(&path).await.unwrap();
+        store.insert(b"a1", b"x").await.unwrap();
+        store.insert(b"a2", b"y").await.unwrap();
+        store.insert(b"b1", b"z").await.unwrap();
+        store.update(b"a1", b"x2").await.unwrap();
+        store.delete(b"a2").await.unwrap();
+
+        assert_eq!(store.get(b"a1").await.unwrap(), Some(b"x2".to_vec()));
+        assert_eq!(store.scan(b"a").await.unwrap(), vec![(b"a1".to_vec(), b"x2".to_vec())]);
+    }
+
+    // the I/O thread releases the file once the handle is dropped
+    let mut reopened = AsyncActionKV::open(&path).await;
+    for _ in 0..100 {
+        if reopened.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        reopened = AsyncActionKV::open(&path).await;
+    }
+    assert_eq!(reopened.unwrap().get(b"b1").await.unwrap(), Some(b"z".to_vec()));
+}
+
+#[tokio::test]
+async fn cancelled_write_is_applied_whole() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AsyncActionKV::open(&dir.path().join("store.akv")).await.unwrap();
+
+    // a zero timeout polls the write once and then drops it
+    let value = vec![7u8; 1 << 20];
+    let _ = tokio::time::timeout(Duration::ZERO, store.insert(b"big", &value)).await;
+
+    assert_eq!(store.get(b"big").await.unwrap(), Some(value));
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+
+use libactionkv::{ActionKV, CachePolicy, CacheStats};
+
+// cache entries cost the key, its 4-byte table prefix and the value
+const VALUE: &[u8] = b"0123456789";
+const ENTRY_COST: usize = 4 + 1 + 10;
+
+fn store(path: &Path, policy: CachePolicy, byte_budget: usize) -> ActionKV {
+    let mut store = ActionKV::open(path).unwrap().with_cache(policy, byte_budget);
+    for key in [b"a", b"b", b"c"] {
+        store.insert(key, VALUE).unwrap();
+    }
+    store
+}
+
+fn stats(store: &ActionKV) -> CacheStats {
+    store.cache_stats().unwrap()
+}
+
+/// Reads `key` and reports whether the cache had it.
+fn hit(store: &mut ActionKV, key: &[u8]) -> bool {
+    let hits = stats(store).hits;
+    assert_eq!(store.get(key).unwrap(), Some(VALUE.to_vec()));
+    stats(store).hits > hits
+}
+
+#[test]
+fn lru_evicts_the_least_recently_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = store(&dir.path().join("store.akv"), CachePolicy::Lru, 2 * ENTRY_COST);
+
+    assert!(!hit(&mut store, b"a"));
+    assert!(!hit(&mut store, b"b"));
+    assert!(hit(&mut store, b"a")); // b is now the least recently read
+
+    assert!(!hit(&mut store, b"c"));
+    assert_eq!(stats(&store).evictions, 1);
+    assert!(hit(&mut store, b"a"));
+    assert!(hit(&mut store, b"c"));
+    assert!(!hit(&mut store, b"b"));
+}
+
+#[test]
+fn clock_gives_read_entries_a_second_chance() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = store(&dir.path().join("store.akv"), CachePolicy::Clock, 2 * ENTRY_COST);
+
+    assert!(!hit(&mut store, b"a"));
+    assert!(!hit(&mut store, b"b"));
+    assert!(hit(&mut store, b"a")); // sets a's reference bit
+
+    // the hand passes over a, clearing its bit, and evicts b
+    assert!(!hit(&mut store, b"c"));
+    assert_eq!(stats(&store).evictions, 1);
+    assert!(hit(&mut store, b"a"));
+    assert!(hit(&mut store, b"c"));
+    assert!(!hit(&mut store, b"b"));
+}
+
+#[test]
+fn zero_budget_caches_nothing() {
+    for policy in [CachePolicy::Lru, CachePolicy::Clock] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir.path().join("store.akv"), policy, 0);
+
+        for _ in 0..3 {
+            assert!(!hit(&mut store, b"a"));
+        }
+
+        let stats = stats(&store);
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 3, 0));
+        assert_eq!((stats.entries, stats.bytes), (0, 0));
+    }
+}
+
+#[test]
+fn writes_invalidate_cached_values() {
+    for policy in [CachePolicy::Lru, CachePolicy::Clock] {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir.path().join("store.akv"), policy, 4 * ENTRY_COST);
+
+        assert!(!hit(&mut store, b"a"));
+        assert!(hit(&mut store, b"a"));
+
+        store.update(b"a", b"new value").unwrap();
+        assert_eq!(stats(&store).entries, 0);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"new value".to_vec()));
+
+        assert!(!hit(&mut store, b"b"));
+        store.delete(b"b").unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(Vec::new())); // the tombstone, not the cached value
+
+        let stats = stats(&store);
+        assert_eq!(stats.hits, 1);
+        // only a's new value and b's tombstone are left
+        assert_eq!((stats.entries, stats.bytes), (2, (4 + 1 + 9) + (4 + 1)));
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libactionkv::{ActionKV, CompactionPolicy, Compactor, SharedActionKV};
+
+#[test]
+fn compact_keeps_live_data_and_drops_the_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    {
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..50u32 {
+            store.insert(b"hot", &i.to_le_bytes()).unwrap();
+        }
+        store.insert(b"gone", b"soon").unwrap();
+        store.delete(b"gone").unwrap();
+        store.open_table("users").unwrap().insert(b"ada", b"lovelace").unwrap();
+        store.open_table("scratch").unwrap().insert(b"tmp", b"x").unwrap();
+        store.drop_table("scratch").unwrap();
+        store.map().unwrap();
+        assert!(store.dead_bytes() > 0);
+        assert_eq!(store.dead_bytes(), store.stats().unwrap().dead_bytes);
+        let dead_records = store.dead_records();
+        assert!(dead_records >= 49);
+        drop(store);
+
+        // what load counts from the file matches what the writes counted as they went
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        store.map().unwrap();
+        assert_eq!(store.dead_records(), dead_records);
+
+        let reclaimed = store.compact().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!((store.dead_bytes(), store.dead_records()), (0, 0));
+        assert_eq!(store.file_len(), std::fs::metadata(&path).unwrap().len());
+
+        assert_eq!(store.get_mapped(b"hot").unwrap().as_deref(), Some(&49u32.to_le_bytes()[..]));
+        assert_eq!(store.get(b"gone").unwrap(), None);
+        store.insert(b"after", b"compaction").unwrap();
+    }
+
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.dead_bytes(), 0);
+    assert_eq!(store.get(b"hot").unwrap(), Some(49u32.to_le_bytes().to_vec()));
+    assert_eq!(store.get(b"after").unwrap(), Some(b"compaction".to_vec()));
+    assert_eq!(store.open_table("users").unwrap().get(b"ada").unwrap(), Some(b"lovelace".to_vec()));
+    assert_eq!(store.list_tables(), vec!["users".to_string()]);
+}
+
+#[test]
+fn background_compactor_keeps_up_with_writers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+    let store = SharedActionKV::new(ActionKV::open(&path).unwrap());
+
+    let policy = CompactionPolicy {
+        min_dead_bytes: 4096,
+        max_bytes_per_sec: Some(1 << 20),
+        check_interval: Duration::from_millis(5),
+        ..CompactionPolicy::default()
+    };
+    let compactor = Compactor::start(&store, policy);
+
+    let value = [7u8; 512];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut i = 0u32;
+    while compactor.compactions() < 2 {
+        assert!(Instant::now() < deadline, "no compaction happened");
+        store.lock().insert(&(i % 8).to_le_bytes(), &value).unwrap();
+        store.lock().insert(b"last", &i.to_le_bytes()).unwrap();
+        i += 1;
+    }
+    compactor.stop().unwrap();
+
+    // writes made while the records were being copied weren't lost
+    let last = store.lock().get(b"last").unwrap();
+    assert_eq!(last, Some((i - 1).to_le_bytes().to_vec()));
+    drop(store);
+
+    let mut reopened = ActionKV::open(&path).unwrap();
+    reopened.load().unwrap();
+    assert_eq!(reopened.get(b"last").unwrap(), Some((i - 1).to_le_bytes().to_vec()));
+    for key in 0..8u32.min(i) {
+        assert_eq!(reopened.get(&key.to_le_bytes()).unwrap(), Some(value.to_vec()));
+    }
+}
+
+#[test]
+fn many_small_dead_records_trigger_a_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SharedActionKV::new(ActionKV::open(&dir.path().join("store.akv")).unwrap());
+
+    // far too few dead bytes for the byte thresholds
+    let policy = CompactionPolicy {
+        min_dead_bytes: u64::MAX,
+        max_dead_records: Some(20),
+        check_interval: Duration::from_millis(5),
+        ..CompactionPolicy::default()
+    };
+    let compactor = Compactor::start(&store, policy);
+
+    for i in 0..=20u8 {
+        store.lock().insert(b"k", &[i]).unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while compactor.compactions() < 1 {
+        assert!(Instant::now() < deadline, "no compaction happened");
+        thread::sleep(Duration::from_millis(5));
+    }
+    compactor.stop().unwrap();
+
+    assert_eq!(store.lock().dead_records(), 0);
+    assert_eq!(store.lock().get(b"k").unwrap(), Some(vec![20]));
+}
+
+#[test]
+fn throttled_writers_wait_without_holding_the_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SharedActionKV::new(ActionKV::open(&dir.path().join("store.akv")).unwrap());
+
+    // a compaction is always due, but the compactor doesn't get to it: every write is throttled
+    let delay = Duration::from_millis(500);
+    let policy = CompactionPolicy {
+        min_dead_ratio: 0.0,
+        min_dead_bytes: 0,
+        throttle_dead_ratio: 0.0,
+        throttle_delay: delay,
+        check_interval: Duration::from_secs(3600),
+        ..CompactionPolicy::default()
+    };
+    let compactor = Compactor::start(&store, policy);
+
+    let written = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, written) = (store.clone(), Arc::clone(&written));
+        thread::spawn(move || {
+            let started = Instant::now();
+            store.lock().insert(b"k", b"v").unwrap();
+            written.store(true, Ordering::SeqCst);
+            started.elapsed()
+        })
+    };
+
+    // the value can be read while the writer is still waiting
+    while store.lock().get(b"k").unwrap().is_none() {
+        thread::yield_now();
+    }
+    assert!(!written.load(Ordering::SeqCst));
+
+    assert!(writer.join().unwrap() >= delay);
+    compactor.stop().unwrap();
+}
+
+#[test]
+fn writes_are_not_throttled_while_no_compaction_is_due() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SharedActionKV::new(ActionKV::open(&dir.path().join("store.akv")).unwrap());
+
+    // nearly all of the file is dead, but far from the default `min_dead_bytes`
+    let delay = Duration::from_millis(500);
+    let policy = CompactionPolicy { throttle_dead_ratio: 0.0, throttle_delay: delay, ..CompactionPolicy::default() };
+    let compactor = Compactor::start(&store, policy);
+
+    let started = Instant::now();
+    for i in 0..20u8 {
+        store.lock().insert(b"k", &[i]).unwrap();
+    }
+    assert!(store.lock().dead_bytes() > 0);
+    assert!(started.elapsed() < delay, "writes took {:?}", started.elapsed());
+
+    assert_eq!(compactor.compactions(), 0);
+    compactor.stop().unwrap();
+}
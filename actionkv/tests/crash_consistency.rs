@@ -0,0 +1,235 @@
+//! Runs random operations against both `ActionKV` and a `BTreeMap` model, then
+//! simulates a crash by cutting the file off at a random byte and checks that
+//! `load` recovers exactly the operations that were fully written.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libactionkv::{ActionKV, KvError};
+use proptest::prelude::*;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Update(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    // a handful of short keys, so that updates and deletes hit existing keys often
+    prop::collection::vec(0u8..4, 1..3)
+}
+
+fn value() -> impl Strategy<Value = Vec<u8>> {
+    // an empty value is how `delete` marks a key as gone, so live values are never empty
+    prop::collection::vec(any::<u8>(), 1..64)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (key(), value()).prop_map(|(k, v)| Op::Insert(k, v)),
+        (key(), value()).prop_map(|(k, v)| Op::Update(k, v)),
+        key().prop_map(Op::Delete),
+    ]
+}
+
+fn apply(store: &mut ActionKV, model: &mut Model, op: &Op) {
+    match op {
+        Op::Insert(k, v) => {
+            store.insert(k, v).unwrap();
+            model.insert(k.clone(), v.clone());
+        }
+        Op::Update(k, v) => {
+            store.update(k, v).unwrap();
+            model.insert(k.clone(), v.clone());
+        }
+        Op::Delete(k) => {
+            store.delete(k).unwrap();
+            model.remove(k);
+        }
+    }
+}
+
+/// Reads every key the model has ever seen back out of the store, dropping tombstones.
+fn recovered_state(store: &mut ActionKV) -> Model {
+    let keys: Vec<Vec<u8>> = store.index.keys().cloned().collect();
+    let mut state = Model::new();
+    for k in keys {
+        match store.get(&k).unwrap() {
+            Some(v) if !v.is_empty() => {
+                state.insert(k, v);
+            }
+            _ => {}
+        }
+    }
+    state
+}
+
+fn file_len(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().len()
+}
+
+/// Writes `ops` to a fresh store and returns the model state and file length after each
+/// acknowledged operation. Index 0 is the empty store.
+fn write_ops(path: &Path, ops: &[Op]) -> (Vec<Model>, Vec<u64>) {
+    let mut store = ActionKV::open(path).unwrap();
+    let mut model = Model::new();
+
+    let mut states = vec![model.clone()];
+    let mut acked_lens = vec![file_len(path)];
+
+    for op in ops {
+        apply(&mut store, &mut model, op);
+        states.push(model.clone());
+        acked_lens.push(file_len(path));
+    }
+
+    (states, acked_lens)
+}
+
+fn crash_at(path: &Path, len: u64) {
+    OpenOptions::new().write(true).open(path).unwrap().set_len(len).unwrap();
+}
+
+proptest! {
+    #[test]
+    fn recovered_state_is_a_prefix_of_acknowledged_ops(
+        ops in prop::collection::vec(op(), 0..40),
+        cut in 0.0f64..=1.0,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let (states, acked_lens) = write_ops(&path, &ops);
+
+        let cut_at = (cut * *acked_lens.last().unwrap() as f64) as u64;
+        crash_at(&path, cut_at);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let recovered = recovered_state(&mut store);
+
+        // every operation whose record ends before the cut survives, the rest are lost
+        let survivors = acked_lens.iter().filter(|len| **len <= cut_at).count().max(1) - 1;
+        prop_assert_eq!(&recovered, &states[survivors]);
+        prop_assert!(states.contains(&recovered));
+    }
+
+    #[test]
+    fn recovered_store_accepts_new_writes(
+        ops in prop::collection::vec(op(), 1..20),
+        cut in 0.0f64..=1.0,
+        extra in (key(), value()),
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let (_, acked_lens) = write_ops(&path, &ops);
+        crash_at(&path, (cut * *acked_lens.last().unwrap() as f64) as u64);
+
+        let expected = {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            store.insert(&extra.0, &extra.1).unwrap();
+            recovered_state(&mut store)
+        };
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        prop_assert_eq!(recovered_state(&mut store), expected);
+    }
+}
+
+#[test]
+fn streamed_write_without_its_checksum_is_trimmed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    let (before, position) = {
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"kept", b"value").unwrap();
+        let before = file_len(&path);
+
+        let value = vec![7u8; 100_000];
+        store.insert_from_reader(b"streamed", value.as_slice(), value.len() as u64).unwrap();
+        (before, store.index[&b"streamed"[..]])
+    };
+
+    // a crash between streaming the value and writing its checksum leaves the placeholder
+    let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(position)).unwrap();
+    f.write_all(&0u32.to_le_bytes()).unwrap();
+    drop(f);
+
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    assert_eq!(file_len(&path), before);
+    assert_eq!(store.get(b"streamed").unwrap(), None);
+    assert_eq!(store.get(b"kept").unwrap(), Some(b"value".to_vec()));
+
+    store.insert(b"after", b"recovery").unwrap();
+    drop(store);
+
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.get(b"after").unwrap(), Some(b"recovery".to_vec()));
+}
+
+#[test]
+fn corrupt_length_before_valid_records_is_not_trimmed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    let position = {
+        let mut store = ActionKV::open(&path).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            store.insert(key, b"value").unwrap();
+        }
+        store.index[&b"a"[..]]
+    };
+    let len = file_len(&path);
+
+    // a's value length now claims more than the rest of the file, b and c included
+    let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(position + 8)).unwrap();
+    f.write_all(&1000u32.to_le_bytes()).unwrap();
+    drop(f);
+
+    let mut store = ActionKV::open(&path).unwrap();
+    match store.load() {
+        Err(KvError::Corruption { offset, .. }) => assert_eq!(offset, position),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(file_len(&path), len);
+}
+
+#[test]
+fn large_torn_value_is_trimmed_quickly() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    let before = {
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"kept", b"value").unwrap();
+        let before = file_len(&path);
+
+        // every offset of the value reads as a record that fits in what was written of it
+        store.insert(b"large", &vec![1u8; 72 << 20]).unwrap();
+        before
+    };
+    crash_at(&path, before + (36 << 20));
+
+    let started = Instant::now();
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10), "load took {:?}", started.elapsed());
+
+    assert_eq!(file_len(&path), before);
+    assert_eq!(store.get(b"large").unwrap(), None);
+    assert_eq!(store.get(b"kept").unwrap(), Some(b"value".to_vec()));
+}
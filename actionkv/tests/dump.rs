@@ -0,0 +1,61 @@
+use libactionkv::{ActionKV, DumpFormat, Encoding};
+
+const FORMATS: [DumpFormat; 3] = [DumpFormat::JsonLines, DumpFormat::Csv, DumpFormat::Sql];
+
+// text that has to be quoted or escaped in every format
+const TRICKY: &[u8] = b"it's a \"quoted\", multi-line;\nvalue";
+
+fn round_trip(pairs: &[(&[u8], &[u8])], format: DumpFormat, encoding: Encoding) {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut source = ActionKV::open(&dir.path().join("source.akv")).unwrap();
+    for (key, value) in pairs {
+        source.insert(key, value).unwrap();
+    }
+    source.insert(b"deleted", b"gone").unwrap();
+    source.delete(b"deleted").unwrap();
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export(&mut dump, format, encoding).unwrap(), pairs.len());
+
+    let mut target = ActionKV::open(&dir.path().join("target.akv")).unwrap();
+    let imported = target.import(&dump[..], format, encoding).unwrap();
+    assert_eq!(imported, pairs.len(), "{} {}:\n{}", format, encoding, String::from_utf8_lossy(&dump));
+
+    for (key, value) in pairs {
+        assert_eq!(target.get(key).unwrap().as_deref(), Some(*value), "{} {}", format, encoding);
+    }
+    assert_eq!(target.get(b"deleted").unwrap(), None);
+}
+
+#[test]
+fn binary_data_round_trips_with_hex_and_base64() {
+    let pairs: [(&[u8], &[u8]); 3] = [(b"\x00\xff", b"\x01\x02\x03"), (b"text", TRICKY), (b"\xc3\x28", b"\xfe")];
+
+    for format in FORMATS {
+        for encoding in [Encoding::Hex, Encoding::Base64] {
+            round_trip(&pairs, format, encoding);
+        }
+    }
+}
+
+#[test]
+fn text_round_trips_with_utf8_lossy() {
+    let pairs: [(&[u8], &[u8]); 2] = [("clé".as_bytes(), TRICKY), (b"x';--", b"'')")];
+
+    for format in FORMATS {
+        round_trip(&pairs, format, Encoding::Utf8Lossy);
+    }
+}
+
+#[test]
+fn malformed_input_reports_the_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+
+    let input = b"{\"key\":\"6b31\",\"value\":\"7631\"}\n{\"key\":\"zz\",\"value\":\"00\"}\n";
+    let err = store.import(&input[..], DumpFormat::JsonLines, Encoding::Hex).unwrap_err();
+
+    assert!(err.to_string().contains("record 2"), "{}", err);
+    assert_eq!(store.get(b"k1").unwrap(), Some(b"v1".to_vec())); // the record before it was kept
+}
@@ -0,0 +1,156 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn start(path: &std::path::Path) -> Server {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_akv_http"))
+        .arg(path)
+        .args(["--listen", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let addr = line.trim().strip_prefix("listening on http://").unwrap().to_string();
+
+    Server { child, addr }
+}
+
+/// Sends one request and returns the status, the headers (lowercased names) and the body.
+fn request(server: &Server, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> (u16, Vec<(String, String)>, Vec<u8>) {
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, body.len()).unwrap();
+    for (name, value) in headers {
+        write!(stream, "{}: {}\r\n", name, value).unwrap();
+    }
+    write!(stream, "\r\n").unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers = lines
+        .filter_map(|line| line.split_once(": "))
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+        .collect();
+
+    (status, headers, response[split + 4..].to_vec())
+}
+
+fn etag_of(headers: &[(String, String)]) -> String {
+    headers.iter().find(|(name, _)| name == "etag").map(|(_, value)| value.clone()).unwrap()
+}
+
+#[test]
+fn crud_with_conditional_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = start(&dir.path().join("store.akv"));
+
+    assert_eq!(request(&server, "GET", "/health", &[], b"").0, 200);
+    assert_eq!(request(&server, "GET", "/kv/missing", &[], b"").0, 404);
+
+    let (status, headers, _) = request(&server, "PUT", "/kv/a%20key", &[], b"v1");
+    assert_eq!(status, 201);
+    let v1 = etag_of(&headers);
+
+    let (status, headers, body) = request(&server, "GET", "/kv/a%20key", &[], b"");
+    assert_eq!((status, body.as_slice()), (200, &b"v1"[..]));
+    assert_eq!(etag_of(&headers), v1);
+    assert_eq!(request(&server, "GET", "/kv/a%20key", &[("If-None-Match", &v1)], b"").0, 304);
+
+    // creating only if absent fails now that the key exists
+    assert_eq!(request(&server, "PUT", "/kv/a%20key", &[("If-None-Match", "*")], b"x").0, 412);
+
+    let (status, headers, _) = request(&server, "PUT", "/kv/a%20key", &[("If-Match", &v1)], b"v2");
+    assert_eq!(status, 204);
+    let v2 = etag_of(&headers);
+    assert_ne!(v1, v2);
+
+    // a write based on the old version is rejected
+    assert_eq!(request(&server, "PUT", "/kv/a%20key", &[("If-Match", &v1)], b"v3").0, 412);
+    assert_eq!(request(&server, "DELETE", "/kv/a%20key", &[("If-Match", &v1)], b"").0, 412);
+
+    request(&server, "PUT", "/kv/other", &[], b"x");
+    let (_, _, body) = request(&server, "GET", "/kv?prefix=a+", &[], b"");
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listing["keys"], serde_json::json!(["a key"]));
+
+    assert_eq!(request(&server, "DELETE", "/kv/a%20key", &[("If-Match", &v2)], b"").0, 204);
+    assert_eq!(request(&server, "GET", "/kv/a%20key", &[], b"").0, 404);
+
+    // deleted keys aren't listed
+    let (_, _, body) = request(&server, "GET", "/kv", &[], b"");
+    let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listing["keys"], serde_json::json!(["other"]));
+
+    let (status, _, body) = request(&server, "GET", "/stats", &[], b"");
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!((status, stats["live_keys"].as_u64(), stats["tombstones"].as_u64()), (200, Some(1), Some(1)));
+
+    // a deleted key that's written again is live again
+    assert_eq!(request(&server, "PUT", "/kv/a%20key", &[], b"v4").0, 201);
+    let (_, _, body) = request(&server, "GET", "/stats", &[], b"");
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!((stats["live_keys"].as_u64(), stats["tombstones"].as_u64()), (Some(2), Some(0)));
+}
+
+#[test]
+fn stats_count_the_keys_already_in_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+    {
+        let mut store = libactionkv::ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.delete(b"b").unwrap();
+    }
+    let server = start(&path);
+
+    let (_, _, body) = request(&server, "GET", "/stats", &[], b"");
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!((stats["live_keys"].as_u64(), stats["tombstones"].as_u64()), (Some(1), Some(1)));
+}
+
+#[test]
+fn malformed_escapes_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = start(&dir.path().join("store.akv"));
+
+    for path in ["/kv/%+1", "/kv/%-1", "/kv/%4", "/kv/%zz"] {
+        assert_eq!(request(&server, "GET", path, &[], b"").0, 400, "{}", path);
+    }
+    assert_eq!(request(&server, "PUT", "/kv/%41", &[], b"x").0, 201);
+    assert_eq!(request(&server, "GET", "/kv/A", &[], b"").2, b"x");
+}
+
+#[test]
+fn oversized_values_are_rejected_before_the_body_is_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = start(&dir.path().join("store.akv"));
+
+    // the body is never sent: the length alone is enough to refuse it
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    write!(stream, "PUT /kv/big HTTP/1.1\r\nHost: test\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", 16 * 1024 * 1024 + 1).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    assert_eq!(request(&server, "GET", "/kv/big", &[], b"").0, 404);
+}
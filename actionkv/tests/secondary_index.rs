@@ -0,0 +1,60 @@
+use libactionkv::{ActionKV, KvError};
+
+// values look like "city,name"; the index is on the city
+fn city(value: &[u8]) -> Option<Vec<u8>> {
+    value.split(|b| *b == b',').next().map(|city| city.to_vec())
+}
+
+#[test]
+fn index_follows_writes_and_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+
+    {
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"1", b"lagos,ada").unwrap(); // written before the index exists
+        store.register_index("city", city).unwrap();
+
+        store.insert(b"2", b"tokyo,ken").unwrap();
+        store.insert(b"3", b"lagos,tunde").unwrap();
+        store.update(b"3", b"tokyo,tunde").unwrap();
+        store.insert(b"4", b"lagos,bisi").unwrap();
+        store.delete(b"4").unwrap();
+
+        let lagos = store.get_by_index("city", b"lagos").unwrap();
+        assert_eq!(lagos, vec![(b"1".to_vec(), b"lagos,ada".to_vec())]);
+    }
+
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    store.register_index("city", city).unwrap();
+
+    let tokyo: Vec<Vec<u8>> = store.get_by_index("city", b"tokyo").unwrap().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(tokyo, vec![b"2".to_vec(), b"3".to_vec()]);
+    assert!(store.list_tables().is_empty());
+
+    assert!(matches!(store.get_by_index("name", b"ken"), Err(KvError::NoSuchIndex { .. })));
+
+    // the index's entries and the table catalog aren't the user's keys
+    let stats = store.stats().unwrap();
+    assert_eq!((stats.live_keys, stats.tombstones), (3, 1));
+    assert_eq!(stats.internal_records, 6); // the catalog entry, 3 live index entries and 2 removed ones
+}
+
+#[test]
+fn index_tables_cant_be_opened_or_dropped_as_tables() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+    store.insert(b"1", b"lagos,ada").unwrap();
+    store.register_index("city", city).unwrap();
+
+    assert!(matches!(store.open_table("+index:city"), Err(KvError::ReservedTableName { .. })));
+    assert!(matches!(store.open_table("+index:other"), Err(KvError::ReservedTableName { .. })));
+    assert!(matches!(store.drop_table("+index:city"), Err(KvError::ReservedTableName { .. })));
+
+    // the index is still registered and still follows writes
+    store.insert(b"2", b"lagos,tunde").unwrap();
+    let lagos: Vec<Vec<u8>> = store.get_by_index("city", b"lagos").unwrap().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(lagos, vec![b"1".to_vec(), b"2".to_vec()]);
+    assert!(store.list_tables().is_empty());
+}
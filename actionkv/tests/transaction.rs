@@ -0,0 +1,108 @@
+use std::thread;
+
+use libactionkv::{ActionKV, KvError, SharedActionKV};
+
+fn read_counter(value: Option<Vec<u8>>) -> u64 {
+    value.map(|bytes| String::from_utf8(bytes).unwrap().parse().unwrap()).unwrap_or(0)
+}
+
+#[test]
+fn concurrent_increments_are_not_lost() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.akv");
+    let store = SharedActionKV::new(ActionKV::open(&path).unwrap());
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    // a heavily contended key may run out of attempts; the caller decides what to do
+                    loop {
+                        let result = store.transaction(|tx| {
+                            let count = read_counter(tx.get(b"counter")?);
+                            tx.insert(b"counter", (count + 1).to_string().as_bytes());
+                            Ok(())
+                        });
+                        match result {
+                            Err(KvError::TransactionConflict { .. }) => continue,
+                            other => break other.unwrap(),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(read_counter(store.lock().get(b"counter").unwrap()), 100);
+}
+
+#[test]
+fn failed_transaction_writes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+    store.insert(b"from", b"10").unwrap();
+
+    let result: Result<(), KvError> = store.transaction(|tx| {
+        tx.insert(b"from", b"0");
+        tx.insert(b"to", b"10");
+        Err(KvError::NoSuchIndex { name: "abort".to_string() })
+    });
+    assert!(result.is_err());
+
+    assert_eq!(store.get(b"from").unwrap(), Some(b"10".to_vec()));
+    assert_eq!(store.get(b"to").unwrap(), None);
+}
+
+#[test]
+fn transaction_sees_its_own_writes_and_deletes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+    store.insert(b"a", b"1").unwrap();
+
+    store
+        .transaction(|tx| {
+            tx.delete(b"a");
+            assert_eq!(tx.get(b"a")?, None);
+            tx.insert(b"b", b"2");
+            assert_eq!(tx.get(b"b")?, Some(b"2".to_vec()));
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(store.get(b"a").unwrap(), Some(Vec::new())); // a tombstone
+    assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn compaction_between_read_and_commit_is_a_conflict() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SharedActionKV::new(ActionKV::open(&dir.path().join("store.akv")).unwrap());
+    store.lock().insert(b"counter", b"1").unwrap();
+
+    let mut attempts = 0;
+    store
+        .transaction(|tx| {
+            attempts += 1;
+            let count = read_counter(tx.get(b"counter")?);
+            if attempts == 1 {
+                // another writer updates the counter, then compaction moves its new record
+                // back to the position this transaction read the old one from
+                let mut other = store.lock();
+                let position = other.index[&b"counter"[..]];
+                other.update(b"counter", b"5").unwrap();
+                other.compact().unwrap();
+                assert_eq!(other.index[&b"counter"[..]], position);
+            }
+            tx.insert(b"counter", (count + 1).to_string().as_bytes());
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(attempts, 2);
+    assert_eq!(read_counter(store.lock().get(b"counter").unwrap()), 6);
+}
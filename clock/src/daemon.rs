@@ -0,0 +1,647 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::ClockError;
+use crate::leap::{announced_leap, next_leap_end, LeapSecondTable};
+use crate::ntp::LeapIndicator;
+use crate::selection::{select, Candidate, Verdict};
+use crate::servers::{query_servers, QueryOptions, ServerAddress};
+use crate::{Clock, Correction};
+
+/// How far the frequency correction may go, as far as the kernel allows.
+const MAX_FREQUENCY_PPM: f64 = 500.0;
+
+/// Share of each new frequency measurement taken into the estimate. The rest is the
+/// previous estimate, which averages out the noise of the offsets.
+const FREQUENCY_GAIN: f64 = 0.25;
+
+/// Polls in a row with the offset within its error bound before polling less often.
+const POLL_HYSTERESIS: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub servers: Vec<ServerAddress>,
+    pub query: QueryOptions,
+    pub step_threshold: ChronoDuration,
+    /// log2 of the shortest and longest intervals between polls, in seconds.
+    pub min_poll: u32,
+    pub max_poll: u32,
+    /// Where the frequency estimate is kept across restarts.
+    pub drift_file: PathBuf,
+    pub status_socket: PathBuf,
+    /// Checked against the leap seconds the servers announce.
+    pub leap_seconds: LeapSecondTable,
+    /// Log the corrections instead of making them.
+    pub dry_run: bool,
+}
+
+/// What the daemon reports on its status socket.
+#[derive(Debug, Clone)]
+struct Status {
+    started: DateTime<Utc>,
+    polls: u64,
+    poll_interval: u64,
+    frequency_ppm: f64,
+    last_poll: Option<DateTime<Utc>>,
+    offset_ms: Option<f64>,
+    error_bound_ms: Option<f64>,
+    accepted: usize,
+    replied: usize,
+    last_correction: Option<String>,
+    last_error: Option<String>,
+    /// The leap second the servers announce, and whether the kernel is armed for it.
+    leap: LeapIndicator,
+    leap_armed: bool,
+}
+
+impl Status {
+    fn report(&self, config: &DaemonConfig) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+        let mut report = String::new();
+        let _ = writeln!(report, "started: {}", self.started.to_rfc3339());
+        let _ = writeln!(report, "servers: {}", config.servers.len());
+        let _ = writeln!(report, "polls: {}", self.polls);
+        let _ = writeln!(report, "poll interval: {}s", self.poll_interval);
+        let _ = writeln!(report, "frequency: {:+.3}ppm", self.frequency_ppm);
+        let _ = writeln!(
+            report,
+            "last poll: {}",
+            optional(self.last_poll.map(|t| t.to_rfc3339()))
+        );
+        let _ = writeln!(
+            report,
+            "offset: {}",
+            optional(self.offset_ms.map(|o| format!("{:+.3}ms", o)))
+        );
+        let _ = writeln!(
+            report,
+            "error bound: {}",
+            optional(self.error_bound_ms.map(|e| format!("{:.3}ms", e)))
+        );
+        let _ = writeln!(
+            report,
+            "accepted: {} of {} replies",
+            self.accepted, self.replied
+        );
+        let _ = writeln!(
+            report,
+            "last correction: {}",
+            optional(self.last_correction.clone())
+        );
+        let _ = writeln!(report, "last error: {}", optional(self.last_error.clone()));
+        let _ = match self.leap {
+            LeapIndicator::AddSecond | LeapIndicator::DeleteSecond => writeln!(
+                report,
+                "leap second: {} before {}{}",
+                self.leap,
+                next_leap_end(Utc::now()).to_rfc3339(),
+                if self.leap_armed { ", armed" } else { "" }
+            ),
+            _ => writeln!(report, "leap second: none"),
+        };
+        report
+    }
+}
+
+/// Keeps the clock in sync with `config.servers` until the process is killed.
+///
+/// Every poll, the servers' offsets are selected and combined as for `check-ntp`, and
+/// the result is slewed away, or stepped if it's above the step threshold. What's left
+/// of the offset at the next poll, after the kernel has made up what it could of the
+/// slew, is put down to the oscillator running fast or slow: it updates the estimate
+/// of the frequency error, which is corrected for and saved to the drift file.
+///
+/// Polls start at `min_poll` and grow towards `max_poll` while the clock stays within
+/// what the servers can measure, dropping back as soon as it doesn't.
+///
+/// # Errors
+///
+/// Fails if the status socket can't be bound. Everything after that is only logged.
+pub fn run(config: DaemonConfig) -> Result<(), io::Error> {
+    let mut frequency_ppm = match read_drift_file(&config.drift_file) {
+        Ok(Some(ppm)) => {
+            log(format!(
+                "read a frequency error of {:+.3}ppm from the drift file",
+                ppm
+            ));
+            ppm
+        }
+        Ok(None) => 0.0,
+        Err(err) => {
+            log(format!("ignoring the drift file: {}", err));
+            0.0
+        }
+    };
+    // without frequency steering the offset is still slewed away, it just comes back sooner
+    let mut steering = true;
+    if !config.dry_run {
+        match Clock::set_frequency(frequency_ppm) {
+            Ok(()) => {}
+            Err(err @ ClockError::Unsupported(_)) => {
+                log(format!("{}, so offsets are only slewed", err));
+                steering = false;
+                frequency_ppm = 0.0;
+            }
+            Err(err) => log(format!("unable to correct the frequency: {}", err)),
+        }
+    }
+
+    let mut polling = Polling::new(config.min_poll, config.max_poll);
+    let status = Arc::new(Mutex::new(Status {
+        started: Utc::now(),
+        polls: 0,
+        poll_interval: polling.interval(),
+        frequency_ppm,
+        last_poll: None,
+        offset_ms: None,
+        error_bound_ms: None,
+        accepted: 0,
+        replied: 0,
+        last_correction: None,
+        last_error: None,
+        leap: LeapIndicator::NoWarning,
+        leap_armed: false,
+    }));
+    serve_status(&config, Arc::clone(&status))?;
+
+    let mut last_slew: Option<DateTime<Utc>> = None; // when the previous offset was slewed
+
+    loop {
+        let measured = measure(&config);
+        let now = Utc::now();
+
+        let mut status_update = status.lock().expect("status mutex poisoned");
+        status_update.polls += 1;
+        status_update.last_poll = Some(now);
+
+        match measured {
+            Err(err) => {
+                log(format!("no usable offset: {}", err));
+                status_update.last_error = Some(err);
+                polling.unusable();
+            }
+            Ok(measurement) => {
+                status_update.offset_ms = Some(measurement.offset_ms);
+                status_update.error_bound_ms = Some(measurement.error_bound_ms);
+                status_update.accepted = measurement.accepted;
+                status_update.replied = measurement.replied;
+
+                if measurement.leap != status_update.leap {
+                    announce_leap(measurement.leap, now, &config.leap_seconds);
+                    status_update.leap = measurement.leap;
+                }
+                let armed = arm_leap(measurement.leap, now, status_update.leap_armed, &config);
+                status_update.leap_armed = armed;
+
+                let offset = ChronoDuration::microseconds((measurement.offset_ms * 1e3) as i64);
+                let correction = Correction::for_offset(offset, config.step_threshold);
+
+                if config.dry_run {
+                    log(format!(
+                        "offset {:+.3}ms, would {}",
+                        measurement.offset_ms, correction
+                    ));
+                } else {
+                    let slewed = correct(
+                        correction,
+                        last_slew,
+                        now,
+                        &mut frequency_ppm,
+                        &mut steering,
+                        &config,
+                    );
+                    match slewed {
+                        Ok(slewed) => {
+                            log(format!(
+                                "offset {:+.3}ms, {}, frequency {:+.3}ppm",
+                                measurement.offset_ms, correction, frequency_ppm
+                            ));
+                            last_slew = if slewed { Some(now) } else { None };
+                        }
+                        Err(err) => {
+                            log(format!("unable to correct the clock: {}", err));
+                            status_update.last_error = Some(err.to_string());
+                            last_slew = None;
+                        }
+                    }
+                }
+                status_update.last_correction = Some(correction.to_string());
+                status_update.frequency_ppm = frequency_ppm;
+
+                polling.measured(measurement.offset_ms, measurement.error_bound_ms);
+            }
+        }
+
+        status_update.poll_interval = polling.interval();
+        drop(status_update);
+
+        thread::sleep(Duration::from_secs(polling.interval()));
+    }
+}
+
+/// The interval between polls, log2 seconds, which grows while the offsets stay small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Polling {
+    min_poll: u32,
+    max_poll: u32,
+    poll: u32,
+    /// Polls in a row with the offset within its error bound.
+    good_polls: u32,
+}
+
+impl Polling {
+    fn new(min_poll: u32, max_poll: u32) -> Self {
+        Polling {
+            min_poll,
+            max_poll,
+            poll: min_poll,
+            good_polls: 0,
+        }
+    }
+
+    /// Seconds until the next poll.
+    fn interval(&self) -> u64 {
+        1 << self.poll
+    }
+
+    /// After a poll without a usable offset: back to polling as often as allowed.
+    fn unusable(&mut self) {
+        self.poll = self.min_poll;
+        self.good_polls = 0;
+    }
+
+    /// After a poll that measured `offset_ms`, give or take `error_bound_ms`.
+    fn measured(&mut self, offset_ms: f64, error_bound_ms: f64) {
+        // an offset the servers can't tell from zero means we can afford to wait longer
+        if offset_ms.abs() <= error_bound_ms {
+            self.good_polls += 1;
+            if self.good_polls >= POLL_HYSTERESIS && self.poll < self.max_poll {
+                self.poll += 1;
+                self.good_polls = 0;
+            }
+        } else {
+            self.poll = self.min_poll.max(self.poll.saturating_sub(1));
+            self.good_polls = 0;
+        }
+    }
+}
+
+struct Measurement {
+    offset_ms: f64,
+    /// The leap second most accepted servers announce.
+    leap: LeapIndicator,
+    /// Half the width of the interval the servers agree on.
+    error_bound_ms: f64,
+    accepted: usize,
+    replied: usize,
+}
+
+fn measure(config: &DaemonConfig) -> Result<Measurement, String> {
+    let (candidates, leaps): (Vec<Candidate>, Vec<LeapIndicator>) =
+        query_servers(&config.servers, config.query)
+            .iter()
+            .filter_map(|server| {
+                let samples = server.samples.as_ref().ok()?;
+                let best = server.best()?;
+                Some((Candidate::from_samples(best, samples), best.reply.leap))
+            })
+            .unzip();
+
+    if candidates.is_empty() {
+        return Err("no server replied".to_string());
+    }
+    let selection = select(&candidates)
+        .ok_or_else(|| "no majority of the servers that replied agree on the time".to_string())?;
+
+    let accepted: Vec<LeapIndicator> = leaps
+        .iter()
+        .zip(&selection.verdicts)
+        .filter(|(_, verdict)| matches!(verdict, Verdict::Accepted { .. }))
+        .map(|(&leap, _)| leap)
+        .collect();
+
+    let (low, high) = selection.interval;
+    Ok(Measurement {
+        offset_ms: selection.offset,
+        leap: announced_leap(&accepted),
+        error_bound_ms: (high - low) / 2.0,
+        accepted: selection
+            .verdicts
+            .iter()
+            .filter(|v| matches!(v, Verdict::Accepted { .. }))
+            .count(),
+        replied: candidates.len(),
+    })
+}
+
+/// Applies `correction`, and if the previous poll's offset was slewed, updates the
+/// frequency estimate with what's left of it. Once the platform turns out not to
+/// support frequency corrections, `steering` is cleared and only the offset is slewed.
+///
+/// # Returns
+///
+/// Whether the correction was a slew, which the next poll can learn from.
+fn correct(
+    correction: Correction,
+    last_slew: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    frequency_ppm: &mut f64,
+    steering: &mut bool,
+    config: &DaemonConfig,
+) -> Result<bool, ClockError> {
+    let offset = match correction {
+        Correction::Slew(offset) => offset,
+        Correction::Step(_) => {
+            correction.apply()?;
+            return Ok(false); // the offset jumped: nothing to learn from until the next slew
+        }
+    };
+
+    // the part of the last slew not made up yet is still in the offset, but it isn't drift
+    let pending = Clock::slew(offset)?;
+
+    if !*steering {
+        return Ok(true);
+    }
+    let estimate = last_slew.and_then(|last_slew| {
+        estimate_frequency(*frequency_ppm, offset - pending, now - last_slew)
+    });
+    if let Some(estimate) = estimate {
+        match Clock::set_frequency(estimate) {
+            Ok(()) => *frequency_ppm = estimate,
+            Err(err @ ClockError::Unsupported(_)) => {
+                log(format!("{}, so offsets are only slewed", err));
+                *steering = false;
+                return Ok(true);
+            }
+            Err(err) => return Err(err),
+        }
+        if let Err(err) = write_drift_file(&config.drift_file, *frequency_ppm) {
+            log(format!("unable to save the drift file: {}", err));
+        }
+    }
+
+    Ok(true)
+}
+
+/// The frequency estimate after the clock drifted by `drift` in the `elapsed` time since
+/// the last slew, or `None` if no time has elapsed to tell a rate from.
+fn estimate_frequency(
+    frequency_ppm: f64,
+    drift: ChronoDuration,
+    elapsed: ChronoDuration,
+) -> Option<f64> {
+    let elapsed = elapsed.num_milliseconds() as f64 / 1e3;
+    if elapsed <= 0.0 {
+        return None;
+    }
+
+    let drift_ms = drift.num_microseconds().unwrap_or(0) as f64 / 1e3;
+    let measured_ppm = drift_ms / elapsed * 1e3; // ms per s, to parts per million
+    Some(
+        (frequency_ppm + FREQUENCY_GAIN * measured_ppm)
+            .clamp(-MAX_FREQUENCY_PPM, MAX_FREQUENCY_PPM),
+    )
+}
+
+fn announce_leap(leap: LeapIndicator, now: DateTime<Utc>, leap_seconds: &LeapSecondTable) {
+    if leap == LeapIndicator::NoWarning {
+        log("the servers no longer announce a leap second".to_string());
+        return;
+    }
+
+    let end = next_leap_end(now);
+    log(format!(
+        "the servers announce a leap second: {} before {} ({})",
+        leap,
+        end.to_rfc3339(),
+        if leap_seconds.lists_leap_at(end) {
+            "listed in the leap second table"
+        } else {
+            "not in the leap second table"
+        }
+    ));
+}
+
+/// Arms the kernel for `leap` on the last day of the month only: it applies a leap
+/// second at the next midnight, and servers announce them weeks ahead.
+///
+/// # Returns
+///
+/// Whether the kernel is armed now.
+fn arm_leap(leap: LeapIndicator, now: DateTime<Utc>, armed: bool, config: &DaemonConfig) -> bool {
+    let due =
+        leap != LeapIndicator::NoWarning && next_leap_end(now) - now <= ChronoDuration::days(1);
+    if due == armed {
+        return armed;
+    }
+
+    let kernel_leap = if due { leap } else { LeapIndicator::NoWarning };
+    if config.dry_run {
+        log(format!(
+            "would set the kernel's leap second to {}",
+            kernel_leap
+        ));
+        return due;
+    }
+    match Clock::set_leap(kernel_leap) {
+        Ok(()) => {
+            log(format!("set the kernel's leap second to {}", kernel_leap));
+            due
+        }
+        Err(err) => {
+            log(format!("unable to set the kernel's leap second: {}", err));
+            armed
+        }
+    }
+}
+
+/// Answers every connection to the status socket with the current status, then
+/// closes it. Replaces a socket file left behind by an earlier run.
+fn serve_status(config: &DaemonConfig, status: Arc<Mutex<Status>>) -> Result<(), io::Error> {
+    match fs::remove_file(&config.status_socket) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&config.status_socket)?;
+    let config = config.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let report = status
+                .lock()
+                .expect("status mutex poisoned")
+                .report(&config);
+            // the client hanging up early is its own business
+            let _ = stream.and_then(|mut stream| stream.write_all(report.as_bytes()));
+        }
+    });
+
+    Ok(())
+}
+
+/// The drift file holds the frequency error in ppm, like `ntpd`'s.
+fn read_drift_file(path: &Path) -> Result<Option<f64>, io::Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    match contents.trim().parse::<f64>() {
+        Ok(ppm) if ppm.abs() <= MAX_FREQUENCY_PPM => Ok(Some(ppm)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't hold a frequency in ppm", path.display()),
+        )),
+    }
+}
+
+/// Writes to a temporary file first, so a crash never leaves a truncated drift file.
+fn write_drift_file(path: &Path, ppm: f64) -> Result<(), io::Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    fs::write(&temporary, format!("{:.3}\n", ppm))?;
+    fs::rename(&temporary, path)
+}
+
+fn log(message: String) {
+    println!("{} {}", Utc::now().to_rfc3339(), message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("clock-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn drift_files_round_trip() {
+        let path = temporary_path("drift-round-trip");
+        write_drift_file(&path, -12.3456).unwrap();
+        let read = read_drift_file(&path);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents, "-12.346\n");
+        assert_eq!(read.unwrap(), Some(-12.346));
+    }
+
+    #[test]
+    fn a_missing_drift_file_is_no_estimate_yet() {
+        let path = temporary_path("drift-missing");
+        assert_eq!(read_drift_file(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_drift_files_are_rejected() {
+        let path = temporary_path("drift-invalid");
+        let mut errors = vec![];
+        for contents in ["600.0\n", "-500.5", "fast", ""] {
+            fs::write(&path, contents).unwrap();
+            errors.push(read_drift_file(&path).map_err(|err| err.kind()));
+        }
+        fs::write(&path, "500.0\n").unwrap();
+        let at_the_limit = read_drift_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(errors
+            .iter()
+            .all(|err| *err == Err(io::ErrorKind::InvalidData)));
+        assert_eq!(at_the_limit.unwrap(), Some(500.0));
+    }
+
+    #[test]
+    fn polls_grow_apart_after_enough_small_offsets() {
+        let mut polling = Polling::new(4, 6);
+        assert_eq!(polling.interval(), 16);
+
+        for _ in 0..POLL_HYSTERESIS - 1 {
+            polling.measured(0.5, 1.0);
+        }
+        assert_eq!(polling.poll, 4);
+        polling.measured(-1.0, 1.0);
+        assert_eq!(polling.poll, 5);
+
+        for _ in 0..3 * POLL_HYSTERESIS {
+            polling.measured(0.0, 1.0);
+        }
+        assert_eq!(polling.poll, 6, "never past max_poll");
+        assert_eq!(polling.interval(), 64);
+    }
+
+    #[test]
+    fn polls_come_closer_after_a_large_offset() {
+        let mut polling = Polling::new(4, 8);
+        polling.poll = 6;
+
+        // a large offset in between starts the count of small ones over
+        for _ in 0..POLL_HYSTERESIS - 1 {
+            polling.measured(0.5, 1.0);
+        }
+        polling.measured(2.0, 1.0);
+        assert_eq!(polling.poll, 5);
+        polling.measured(0.5, 1.0);
+        assert_eq!(polling.poll, 5);
+
+        polling.measured(2.0, 1.0);
+        polling.measured(2.0, 1.0);
+        assert_eq!(polling.poll, 4, "never below min_poll");
+
+        polling.poll = 8;
+        polling.unusable();
+        assert_eq!(polling, Polling::new(4, 8));
+    }
+
+    #[test]
+    fn frequency_estimates_take_in_a_share_of_the_drift() {
+        // 1ms gained in 1000s is 1ppm, of which the estimate takes a quarter
+        let estimate = estimate_frequency(
+            0.0,
+            ChronoDuration::milliseconds(1),
+            ChronoDuration::seconds(1000),
+        );
+        assert_eq!(estimate, Some(0.25));
+
+        let estimate = estimate_frequency(
+            10.0,
+            ChronoDuration::milliseconds(-8),
+            ChronoDuration::seconds(1000),
+        );
+        assert_eq!(estimate, Some(8.0));
+    }
+
+    #[test]
+    fn frequency_estimates_stay_within_what_the_kernel_takes() {
+        let hour = ChronoDuration::hours(1);
+        assert_eq!(
+            estimate_frequency(490.0, ChronoDuration::seconds(1), hour),
+            Some(MAX_FREQUENCY_PPM)
+        );
+        assert_eq!(
+            estimate_frequency(-490.0, ChronoDuration::seconds(-1), hour),
+            Some(-MAX_FREQUENCY_PPM)
+        );
+    }
+
+    #[test]
+    fn no_frequency_is_estimated_without_elapsed_time() {
+        let drift = ChronoDuration::milliseconds(1);
+        assert_eq!(estimate_frequency(3.0, drift, ChronoDuration::zero()), None);
+        assert_eq!(
+            estimate_frequency(3.0, drift, ChronoDuration::seconds(-1)),
+            None
+        );
+    }
+}
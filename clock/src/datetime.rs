@@ -0,0 +1,455 @@
+use chrono::{
+    DateTime, Duration as ChronoDuration, FixedOffset, Local, LocalResult, NaiveDate,
+    NaiveDateTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use std::fmt::{self, Write as _};
+use std::str::FromStr;
+
+/// The time zone times are shown in, and naive times are read in: `local`, an IANA
+/// name like `Europe/Paris` or `UTC`, or a fixed offset like `+05:30`.
+#[derive(Debug, Clone, Copy)]
+pub enum Zone {
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Zone::Local);
+        }
+        if s.starts_with('+') || s.starts_with('-') {
+            return parse_offset(s)
+                .map(Zone::Fixed)
+                .ok_or_else(|| format!("invalid UTC offset {:?}, expected e.g. +05:30", s));
+        }
+        s.parse::<Tz>()
+            .map(Zone::Named)
+            .map_err(|_| format!("unknown time zone {:?}", s))
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Zone::Local => write!(f, "local"),
+            Zone::Fixed(offset) => write!(f, "{}", offset),
+            Zone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// `+HH:MM`, `+HHMM` or `+HH`, and the same with `-`.
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let sign = if s.starts_with('-') { -1 } else { 1 };
+    let digits: String = s[1..].chars().filter(|&c| c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (
+            digits[..2].parse::<i32>().ok()?,
+            digits[2..].parse::<i32>().ok()?,
+        ),
+        _ => return None,
+    };
+    if minutes >= 60 {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl Zone {
+    /// Renders `t` in this zone: with `format` if given, a strftime pattern, otherwise as
+    /// `std` says (`rfc2822`, `rfc3339` or `timestamp`).
+    ///
+    /// # Errors
+    ///
+    /// Fails if `format` isn't a valid strftime pattern.
+    pub fn format(
+        &self,
+        t: DateTime<Utc>,
+        std: &str,
+        format: Option<&str>,
+    ) -> Result<String, String> {
+        match self {
+            Zone::Local => render(t.with_timezone(&Local), std, format),
+            Zone::Fixed(offset) => render(t.with_timezone(offset), std, format),
+            Zone::Named(tz) => render(t.with_timezone(tz), std, format),
+        }
+    }
+
+    /// The abbreviation in use in this zone at `t`, like `CEST` or `WAT`, where the tz
+    /// database has one. Fixed offsets and some zones only have their offset.
+    pub fn abbreviation(&self, t: DateTime<Utc>) -> String {
+        match self {
+            Zone::Local => t.with_timezone(&Local).format("%:z").to_string(),
+            Zone::Fixed(offset) => t.with_timezone(offset).format("%:z").to_string(),
+            Zone::Named(tz) => t.with_timezone(tz).format("%Z").to_string(),
+        }
+    }
+
+    /// Places a time without an offset in this zone.
+    ///
+    /// A time that occurs twice, when the clocks go back, is taken as the first one.
+    fn resolve(&self, naive: &NaiveDateTime) -> Result<DateTime<Utc>, String> {
+        let resolved = match self {
+            Zone::Local => to_utc(Local.from_local_datetime(naive)),
+            Zone::Fixed(offset) => to_utc(offset.from_local_datetime(naive)),
+            Zone::Named(tz) => to_utc(tz.from_local_datetime(naive)),
+        };
+
+        resolved.ok_or_else(|| format!("{} doesn't exist in this time zone", naive))
+    }
+}
+
+fn render<Tz: TimeZone>(t: DateTime<Tz>, std: &str, format: Option<&str>) -> Result<String, String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let format = match (format, std) {
+        (Some(format), _) => format,
+        (None, "timestamp") => return Ok(t.timestamp().to_string()),
+        (None, "rfc2822") => return Ok(t.to_rfc2822()),
+        (None, _) => return Ok(t.to_rfc3339()),
+    };
+
+    // chrono only finds out that a pattern is invalid while rendering it
+    let mut rendered = String::new();
+    write!(rendered, "{}", t.format(format))
+        .map_err(|_| format!("{:?} isn't a valid format", format))?;
+    Ok(rendered)
+}
+
+/// Renders a TAI time like `Zone::format` would a UTC one, marked `TAI` instead of
+/// with an offset, since TAI has none.
+///
+/// # Errors
+///
+/// Fails if `format` asks for an offset or time zone.
+pub fn format_tai(t: NaiveDateTime, std: &str, format: Option<&str>) -> Result<String, String> {
+    let pattern = match (format, std) {
+        (Some(format), _) => format,
+        (None, "timestamp") => return Ok(t.and_utc().timestamp().to_string()),
+        (None, "rfc2822") => "%a, %e %b %Y %H:%M:%S TAI",
+        (None, _) => "%Y-%m-%dT%H:%M:%S%.f TAI",
+    };
+
+    let mut rendered = String::new();
+    write!(rendered, "{}", t.format(pattern)).map_err(|_| {
+        format!(
+            "{:?} can't be used for TAI, which has no time zone",
+            pattern
+        )
+    })?;
+    Ok(rendered)
+}
+
+fn to_utc<Tz: TimeZone>(local: LocalResult<DateTime<Tz>>) -> Option<DateTime<Utc>> {
+    match local {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.with_timezone(&Utc)),
+        LocalResult::None => None, // skipped when the clocks went forward
+    }
+}
+
+/// Ways of writing an RFC 3339 time without its offset, which `parse_time` reads in
+/// the zone it's given. `%.f` also matches no fraction at all.
+const NAIVE_LAYOUTS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+];
+
+/// Reads the time `clock set` or `clock convert` was given, in the first of these that applies:
+///
+/// - a time relative to `now`, like `+5m` or `-2h30m`
+/// - the strftime pattern `format`; when it has no offset, the time is read in `zone`
+/// - the standard `std`: `rfc2822`, `rfc3339` or `timestamp`, a UNIX timestamp which
+///   may have a fractional part. With `rfc3339` the offset may be left out, as in
+///   `2024-01-01 12:00` or just `2024-01-01`, and the time is read in `zone`
+pub fn parse_time(
+    input: &str,
+    std: &str,
+    format: Option<&str>,
+    zone: &Zone,
+    now: DateTime<Utc>,
+) -> Result<DateTime<Utc>, String> {
+    if let Some(delta) = parse_relative(input) {
+        return now
+            .checked_add_signed(delta)
+            .ok_or_else(|| format!("{} is too far from now", input));
+    }
+
+    if let Some(format) = format {
+        if let Ok(t) = DateTime::parse_from_str(input, format) {
+            return Ok(t.with_timezone(&Utc));
+        }
+        let naive = NaiveDateTime::parse_from_str(input, format)
+            .map_err(|err| format!("{:?} doesn't match {:?}: {}", input, format, err))?;
+        return zone.resolve(&naive);
+    }
+
+    let parsed = match std {
+        "rfc2822" => DateTime::parse_from_rfc2822(input),
+        "rfc3339" => DateTime::parse_from_rfc3339(input),
+        _ => return parse_timestamp(input),
+    };
+    match parsed {
+        Ok(t) => Ok(t.with_timezone(&Utc)),
+        Err(err) => match parse_naive(input).filter(|_| std == "rfc3339") {
+            Some(naive) => zone.resolve(&naive),
+            None => Err(format!("{:?} isn't {}: {}", input, std, err)),
+        },
+    }
+}
+
+/// An RFC 3339 time without an offset, in one of `NAIVE_LAYOUTS`, or a date alone,
+/// which stands for its midnight.
+fn parse_naive(input: &str) -> Option<NaiveDateTime> {
+    NAIVE_LAYOUTS
+        .iter()
+        .find_map(|layout| NaiveDateTime::parse_from_str(input, layout).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+/// Seconds since 1970-01-01 00:00:00 UTC, with up to nine decimals.
+fn parse_timestamp(input: &str) -> Result<DateTime<Utc>, String> {
+    let invalid = || format!("{:?} isn't a UNIX timestamp", input);
+
+    let (whole, fraction) = input.split_once('.').unwrap_or((input, ""));
+    if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let seconds: i64 = whole.parse().map_err(|_| invalid())?;
+    let mut nanos: i64 = format!("{:0<9}", fraction).parse().map_err(|_| invalid())?;
+    if whole.starts_with('-') {
+        nanos = -nanos; // -1.5 is a second and a half before the epoch
+    }
+
+    let total = seconds as i128 * 1_000_000_000 + nanos as i128;
+    let (seconds, nanos) = (
+        total.div_euclid(1_000_000_000),
+        total.rem_euclid(1_000_000_000),
+    );
+
+    i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| Utc.timestamp_opt(seconds, nanos as u32).single())
+        .ok_or_else(|| format!("{} is out of range", input))
+}
+
+/// A signed sequence of amounts and units, like `+90s`, `-2h30m` or `+1d12h`.
+/// Units are `w`, `d`, `h`, `m`, `s` and `ms`.
+fn parse_relative(input: &str) -> Option<ChronoDuration> {
+    let (sign, mut rest) = match input.as_bytes().first()? {
+        b'+' => (1, &input[1..]),
+        b'-' => (-1, &input[1..]),
+        _ => return None,
+    };
+    if rest.is_empty() {
+        return None;
+    }
+
+    let mut total = ChronoDuration::zero();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+
+        let units = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_ms: i64 = match &rest[..units] {
+            "w" => 7 * 24 * 3_600_000,
+            "d" => 24 * 3_600_000,
+            "h" => 3_600_000,
+            "m" => 60_000,
+            "s" => 1_000,
+            "ms" => 1,
+            _ => return None, // includes a bare number, which is a timestamp
+        };
+        rest = &rest[units..];
+
+        let part = ChronoDuration::milliseconds(amount.checked_mul(unit_ms)?);
+        total = total.checked_add(&part)?;
+    }
+
+    Some(total * sign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_times() {
+        assert_eq!(parse_relative("+90s"), Some(ChronoDuration::seconds(90)));
+        assert_eq!(
+            parse_relative("-2h30m"),
+            Some(-ChronoDuration::minutes(150))
+        );
+        assert_eq!(
+            parse_relative("+1w1d12h"),
+            Some(ChronoDuration::hours(7 * 24 + 24 + 12))
+        );
+        assert_eq!(
+            parse_relative("+1s250ms"),
+            Some(ChronoDuration::milliseconds(1250))
+        );
+    }
+
+    #[test]
+    fn relative_times_need_a_sign_and_units() {
+        for input in ["90s", "+", "+90", "+h", "+5y", "+5m3", "-1.5h", ""] {
+            assert_eq!(parse_relative(input), None, "{:?}", input);
+        }
+        // too large for a duration in milliseconds
+        assert_eq!(parse_relative(&format!("+{}w", i64::MAX)), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let t = parse_timestamp("1700000000").unwrap();
+        assert_eq!(
+            (t.timestamp(), t.timestamp_subsec_nanos()),
+            (1_700_000_000, 0)
+        );
+
+        let t = parse_timestamp("1700000000.25").unwrap();
+        assert_eq!(t.timestamp_subsec_nanos(), 250_000_000);
+
+        let t = parse_timestamp("0.000000001").unwrap();
+        assert_eq!(t.timestamp_subsec_nanos(), 1);
+
+        // a second and a half before the epoch
+        let t = parse_timestamp("-1.5").unwrap();
+        assert_eq!(
+            (t.timestamp(), t.timestamp_subsec_nanos()),
+            (-2, 500_000_000)
+        );
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        for input in ["", "1.5.5", "1.0000000001", "1e9", "1.-5", "abc", "1.5x"] {
+            assert!(parse_timestamp(input).is_err(), "{:?}", input);
+        }
+        assert!(parse_timestamp(&i64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn rfc3339_times_without_an_offset_are_read_in_the_zone() {
+        let zone = Zone::Fixed(FixedOffset::east_opt(2 * 3600).unwrap());
+        let now = Utc::now();
+        let noon = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+
+        for input in [
+            "2024-01-01 12:00",
+            "2024-01-01T12:00",
+            "2024-01-01 12:00:00",
+            "2024-01-01T12:00:00.000",
+        ] {
+            assert_eq!(
+                parse_time(input, "rfc3339", None, &zone, now),
+                Ok(noon),
+                "{:?}",
+                input
+            );
+        }
+        assert_eq!(
+            parse_time("2024-01-01", "rfc3339", None, &zone, now),
+            Ok(noon - ChronoDuration::hours(12))
+        );
+
+        // an offset in the input wins over the zone
+        assert_eq!(
+            parse_time("2024-01-01T12:00:00Z", "rfc3339", None, &zone, now),
+            Ok(noon + ChronoDuration::hours(2))
+        );
+        assert!(parse_time("2024-01-01 12", "rfc3339", None, &zone, now).is_err());
+        assert!(parse_time("2024-01-01 12:00", "rfc2822", None, &zone, now).is_err());
+    }
+
+    #[test]
+    fn times_skipped_by_daylight_saving_are_an_error() {
+        let paris: Zone = "Europe/Paris".parse().unwrap();
+
+        // the clocks went from 02:00 to 03:00 on 2024-03-31
+        let err = parse_time("2024-03-31 02:30", "rfc3339", None, &paris, Utc::now()).unwrap_err();
+        assert_eq!(err, "2024-03-31 02:30:00 doesn't exist in this time zone");
+        assert_eq!(
+            parse_time("2024-03-31 03:30", "rfc3339", None, &paris, Utc::now()),
+            Ok(Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn times_repeated_by_daylight_saving_are_the_first_occurrence() {
+        let paris: Zone = "Europe/Paris".parse().unwrap();
+
+        // the clocks went from 03:00 back to 02:00 on 2024-10-27: 02:30 CEST comes first
+        let t = parse_time("2024-10-27 02:30", "rfc3339", None, &paris, Utc::now()).unwrap();
+        assert_eq!(t, Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap());
+        assert_eq!(paris.abbreviation(t), "CEST");
+
+        let pattern = Some("%d/%m/%Y %H:%M");
+        let t = parse_time("27/10/2024 02:30", "rfc3339", pattern, &paris, Utc::now()).unwrap();
+        assert_eq!(t, Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn times_convert_between_named_zones() {
+        let paris: Zone = "Europe/Paris".parse().unwrap();
+        let tokyo: Zone = "Asia/Tokyo".parse().unwrap();
+        let lagos: Zone = "Africa/Lagos".parse().unwrap();
+
+        let summer = parse_time("2024-07-01 09:00", "rfc3339", None, &paris, Utc::now()).unwrap();
+        assert_eq!(
+            tokyo.format(summer, "rfc3339", None).unwrap(),
+            "2024-07-01T16:00:00+09:00"
+        );
+        assert_eq!(
+            lagos.format(summer, "rfc3339", None).unwrap(),
+            "2024-07-01T08:00:00+01:00"
+        );
+
+        // Lagos keeps +01:00 all year, while Paris is on it in winter
+        let winter = parse_time("2024-01-15 09:00", "rfc3339", None, &paris, Utc::now()).unwrap();
+        assert_eq!(
+            tokyo.format(winter, "rfc3339", None).unwrap(),
+            "2024-01-15T17:00:00+09:00"
+        );
+        assert_eq!(
+            lagos.format(winter, "rfc3339", Some("%H:%M %Z")).unwrap(),
+            "09:00 WAT"
+        );
+        assert_eq!(tokyo.abbreviation(winter), "JST");
+    }
+
+    #[test]
+    fn invalid_formats_are_an_error() {
+        let t = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let zone = Zone::Fixed(FixedOffset::east_opt(3600).unwrap());
+
+        assert_eq!(
+            zone.format(t, "rfc3339", Some("%Y-%m-%d %H:%M")).unwrap(),
+            "2024-01-01 13:00"
+        );
+        assert!(zone.format(t, "rfc3339", Some("%Q")).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::io;
+
+#[cfg(not(windows))]
+const PERMISSION_DENIED: &[i32] = &[libc::EPERM, libc::EACCES];
+#[cfg(not(windows))]
+const INVALID_TIME: &[i32] = &[libc::EINVAL, libc::EOVERFLOW];
+
+#[cfg(windows)]
+const PERMISSION_DENIED: &[i32] = &[5, 1314]; // ERROR_ACCESS_DENIED, ERROR_PRIVILEGE_NOT_HELD
+#[cfg(windows)]
+const INVALID_TIME: &[i32] = &[87]; // ERROR_INVALID_PARAMETER
+
+/// Everything that can stop the clock from being changed.
+#[derive(Debug)]
+pub enum ClockError {
+    /// The process may not change the clock: it needs `CAP_SYS_TIME` on Linux, root on
+    /// other Unixes and `SeSystemtimePrivilege` on Windows.
+    PermissionDenied,
+    /// The system won't take this time or adjustment, usually because it's out of range.
+    InvalidTime(String),
+    /// This platform can't do what was asked.
+    Unsupported(&'static str),
+    /// Any other failure reported by the system.
+    Os(io::Error),
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockError::PermissionDenied => {
+                write!(
+                    f,
+                    "permission denied (changing the clock needs CAP_SYS_TIME or root)"
+                )
+            }
+            ClockError::InvalidTime(reason) => write!(f, "invalid time: {}", reason),
+            ClockError::Unsupported(what) => write!(f, "{} isn't supported on this platform", what),
+            ClockError::Os(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClockError::Os(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ClockError {
+    /// Classifies the error the last system call left behind.
+    pub fn last_os_error() -> Self {
+        io::Error::last_os_error().into()
+    }
+
+    /// The process exit status for this error, from `sysexits.h`, so scripts can
+    /// tell a missing privilege from a bad time.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClockError::PermissionDenied => 77, // EX_NOPERM
+            ClockError::InvalidTime(_) => 65,   // EX_DATAERR
+            ClockError::Unsupported(_) => 69,   // EX_UNAVAILABLE
+            ClockError::Os(_) => 71,            // EX_OSERR
+        }
+    }
+}
+
+impl From<io::Error> for ClockError {
+    fn from(err: io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(code) if PERMISSION_DENIED.contains(&code) => ClockError::PermissionDenied,
+            Some(code) if INVALID_TIME.contains(&code) => ClockError::InvalidTime(err.to_string()),
+            _ => ClockError::Os(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(code: i32) -> ClockError {
+        io::Error::from_raw_os_error(code).into()
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn system_errors_are_classified() {
+        assert!(matches!(
+            classify(libc::EPERM),
+            ClockError::PermissionDenied
+        ));
+        assert!(matches!(
+            classify(libc::EACCES),
+            ClockError::PermissionDenied
+        ));
+        assert!(matches!(classify(libc::EINVAL), ClockError::InvalidTime(_)));
+        assert!(matches!(
+            classify(libc::EOVERFLOW),
+            ClockError::InvalidTime(_)
+        ));
+        match classify(libc::EIO) {
+            ClockError::Os(err) => assert_eq!(err.raw_os_error(), Some(libc::EIO)),
+            other => panic!("expected an OS error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_without_an_os_code_are_os_errors() {
+        let err: ClockError = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert!(matches!(err, ClockError::Os(_)));
+    }
+
+    #[test]
+    fn each_kind_of_error_has_its_own_exit_code() {
+        let errors = [
+            classify(PERMISSION_DENIED[0]),
+            classify(INVALID_TIME[0]),
+            ClockError::Unsupported("slewing"),
+            io::Error::other("failed").into(),
+        ];
+        let codes: Vec<i32> = errors.iter().map(ClockError::exit_code).collect();
+        assert_eq!(codes, [77, 65, 69, 71]);
+    }
+
+    #[test]
+    fn invalid_times_say_why() {
+        let err = classify(INVALID_TIME[0]);
+        let reason = io::Error::from_raw_os_error(INVALID_TIME[0]).to_string();
+        assert_eq!(err.to_string(), format!("invalid time: {}", reason));
+    }
+}
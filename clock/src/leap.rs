@@ -0,0 +1,293 @@
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDateTime, TimeZone, Utc};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::ntp::{LeapIndicator, NTP_TO_UNIX_SECONDS};
+
+/// The table built in, for when no other is given. It's only as up to date as this
+/// build: check `expires` before trusting it with recent times.
+const BUNDLED: &str = include_str!("leap-seconds.list");
+
+/// Where tz databases install the table, kept up to date with them.
+const SYSTEM_TABLE: &str = "/usr/share/zoneinfo/leap-seconds.list";
+
+/// When UTC has been leap seconds behind TAI, as listed by the IERS.
+#[derive(Debug, Clone)]
+pub struct LeapSecondTable {
+    /// The first second after each leap, and TAI - UTC from then on, in order.
+    leaps: Vec<(DateTime<Utc>, i32)>,
+    /// After this, leap seconds may have been announced that the table doesn't list.
+    expires: Option<DateTime<Utc>>,
+}
+
+impl LeapSecondTable {
+    /// Whichever of the tz database's table and the bundled one expires later. A table
+    /// without an expiry never goes stale, so it's preferred.
+    pub fn system_or_bundled() -> Self {
+        let bundled = Self::parse(BUNDLED).expect("the bundled leap second table is valid");
+        match Self::load(Path::new(SYSTEM_TABLE)) {
+            Ok(system) => Self::later_expiring(system, bundled),
+            Err(_) => bundled,
+        }
+    }
+
+    fn later_expiring(a: Self, b: Self) -> Self {
+        match (a.expires, b.expires) {
+            (Some(a_expires), Some(b_expires)) if b_expires > a_expires => b,
+            (Some(_), None) => b,
+            _ => a,
+        }
+    }
+
+    /// Reads a table in the format of the IERS's `leap-seconds.list`, like the one
+    /// tz databases install as `/usr/share/zoneinfo/leap-seconds.list`.
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Parses `leap-seconds.list`: a line per leap second, with the NTP seconds at
+    /// which it ends and TAI - UTC from then on, and the expiry on a `#@` line. Other
+    /// lines starting with `#`, and everything after a `#` on a line, are comments.
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut leaps: Vec<(DateTime<Utc>, i32)> = vec![];
+        let mut expires = None;
+
+        for (n, line) in contents.lines().enumerate() {
+            let invalid = || format!("line {} isn't a leap second: {:?}", n + 1, line);
+
+            if let Some(seconds) = line.strip_prefix("#@") {
+                let seconds = seconds.trim().parse().map_err(|_| invalid())?;
+                expires = Some(from_ntp_seconds(seconds).ok_or_else(invalid)?);
+                continue;
+            }
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(seconds), Some(offset), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let from = seconds
+                .parse()
+                .ok()
+                .and_then(from_ntp_seconds)
+                .ok_or_else(invalid)?;
+            let offset = offset.parse().map_err(|_| invalid())?;
+
+            if leaps.last().is_some_and(|&(last, _)| last >= from) {
+                return Err(format!("line {} is out of order", n + 1));
+            }
+            leaps.push((from, offset));
+        }
+
+        if leaps.is_empty() {
+            return Err("no leap seconds listed".to_string());
+        }
+        Ok(LeapSecondTable { leaps, expires })
+    }
+
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires
+    }
+
+    /// TAI - UTC at `t`, in seconds.
+    ///
+    /// # Returns
+    ///
+    /// `None` before 1972, when UTC wasn't yet kept a whole number of seconds from TAI.
+    pub fn tai_minus_utc(&self, t: DateTime<Utc>) -> Option<i32> {
+        self.leaps
+            .iter()
+            .take_while(|&&(from, _)| from <= t)
+            .last()
+            .map(|&(_, offset)| offset)
+    }
+
+    /// The TAI time at the UTC time `t`. TAI has no time zones, so it's naive.
+    pub fn to_tai(&self, t: DateTime<Utc>) -> Option<NaiveDateTime> {
+        let offset = self.tai_minus_utc(t)?;
+        Some(t.naive_utc() + ChronoDuration::seconds(offset as i64))
+    }
+
+    /// Whether the table lists a leap second ending at `t`.
+    pub fn lists_leap_at(&self, t: DateTime<Utc>) -> bool {
+        self.leaps.iter().any(|&(from, _)| from == t)
+    }
+}
+
+/// When a leap second announced today by an NTP server would end: midnight UTC at
+/// the end of the month, since leap seconds only ever come at the end of a month.
+pub fn next_leap_end(now: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match now.month() {
+        12 => (now.year() + 1, 1),
+        month => (now.year(), month + 1),
+    };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0)
+        .single()
+        .expect("midnight UTC always exists")
+}
+
+/// The leap second most of `announced`, the leap indicators of the servers whose
+/// offsets were accepted, warn of. A leap second only a minority warns of isn't believed.
+pub fn announced_leap(announced: &[LeapIndicator]) -> LeapIndicator {
+    [LeapIndicator::AddSecond, LeapIndicator::DeleteSecond]
+        .into_iter()
+        .find(|&leap| 2 * announced.iter().filter(|&&a| a == leap).count() > announced.len())
+        .unwrap_or(LeapIndicator::NoWarning)
+}
+
+fn from_ntp_seconds(seconds: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(seconds - NTP_TO_UNIX_SECONDS, 0).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A release of this crate should ship with a table it can trust for a while yet.
+    const MIN_DAYS_LEFT: i64 = 30;
+
+    const SHORT_LIST: &str = "\
+#	A comment, with a # in it
+#@	3944332800
+#
+2272060800	10	# 1 Jan 1972
+2287785600	11	# 1 Jul 1972
+
+3692217600	37	# 1 Jan 2017
+";
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, min, sec)
+            .single()
+            .unwrap()
+    }
+
+    // depends on today's date, run with `cargo test -- --ignored` before a release
+    #[test]
+    #[ignore]
+    fn bundled_table_is_not_about_to_expire() {
+        let table = LeapSecondTable::parse(BUNDLED).unwrap();
+        let expires = table.expires().expect("the bundled table has an expiry");
+        assert!(
+            expires > Utc::now() + ChronoDuration::days(MIN_DAYS_LEFT),
+            "the bundled leap second table expires on {}, update src/leap-seconds.list",
+            expires
+        );
+    }
+
+    #[test]
+    fn lists_are_parsed_with_their_expiry() {
+        let table = LeapSecondTable::parse(SHORT_LIST).unwrap();
+        assert_eq!(
+            table.leaps,
+            vec![
+                (utc(1972, 1, 1, 0, 0, 0), 10),
+                (utc(1972, 7, 1, 0, 0, 0), 11),
+                (utc(2017, 1, 1, 0, 0, 0), 37),
+            ]
+        );
+        assert_eq!(table.expires(), Some(utc(2024, 12, 28, 0, 0, 0)));
+
+        let unexpiring = LeapSecondTable::parse("2272060800 10\n").unwrap();
+        assert_eq!(unexpiring.expires(), None);
+    }
+
+    #[test]
+    fn the_table_that_expires_later_is_used() {
+        let older = LeapSecondTable::parse(SHORT_LIST).unwrap();
+        let newer =
+            LeapSecondTable::parse(&SHORT_LIST.replace("3944332800", "4007404800")).unwrap();
+        let unexpiring = LeapSecondTable::parse("2272060800 10\n").unwrap();
+
+        let expires = |a: &LeapSecondTable, b: &LeapSecondTable| {
+            LeapSecondTable::later_expiring(a.clone(), b.clone()).expires()
+        };
+        assert_eq!(expires(&older, &newer), newer.expires());
+        assert_eq!(expires(&newer, &older), newer.expires());
+        assert_eq!(expires(&newer, &unexpiring), None);
+        assert_eq!(expires(&unexpiring, &older), None);
+    }
+
+    #[test]
+    fn invalid_lists_are_rejected() {
+        for list in [
+            "",
+            "# only comments\n",
+            "2272060800\n",
+            "2272060800 10 11\n",
+            "2272060800 ten\n",
+            "#@ soon\n2272060800 10\n",
+        ] {
+            assert!(LeapSecondTable::parse(list).is_err(), "{:?}", list);
+        }
+
+        let out_of_order = LeapSecondTable::parse("2287785600 11\n2272060800 10\n");
+        assert_eq!(out_of_order.unwrap_err(), "line 2 is out of order");
+    }
+
+    #[test]
+    fn offsets_change_when_a_leap_second_ends() {
+        let table = LeapSecondTable::parse(SHORT_LIST).unwrap();
+
+        assert_eq!(table.tai_minus_utc(utc(1971, 12, 31, 23, 59, 59)), None);
+        assert_eq!(table.tai_minus_utc(utc(1972, 1, 1, 0, 0, 0)), Some(10));
+        assert_eq!(table.tai_minus_utc(utc(2016, 12, 31, 23, 59, 59)), Some(11));
+        assert_eq!(table.tai_minus_utc(utc(2017, 1, 1, 0, 0, 0)), Some(37));
+        assert_eq!(table.tai_minus_utc(utc(2026, 1, 1, 0, 0, 0)), Some(37));
+
+        assert_eq!(
+            table.to_tai(utc(2017, 1, 1, 0, 0, 0)),
+            Some(utc(2017, 1, 1, 0, 0, 37).naive_utc())
+        );
+        assert_eq!(table.to_tai(utc(1970, 1, 1, 0, 0, 0)), None);
+
+        assert!(table.lists_leap_at(utc(2017, 1, 1, 0, 0, 0)));
+        assert!(!table.lists_leap_at(utc(2016, 12, 31, 23, 59, 59)));
+    }
+
+    #[test]
+    fn the_bundled_table_agrees_with_the_last_leap_second() {
+        let table = LeapSecondTable::parse(BUNDLED).unwrap();
+        assert_eq!(table.tai_minus_utc(utc(2016, 12, 31, 23, 59, 59)), Some(36));
+        assert_eq!(table.tai_minus_utc(utc(2017, 1, 1, 0, 0, 0)), Some(37));
+    }
+
+    #[test]
+    fn leap_seconds_end_at_the_start_of_the_next_month() {
+        assert_eq!(
+            next_leap_end(utc(2016, 12, 1, 12, 0, 0)),
+            utc(2017, 1, 1, 0, 0, 0)
+        );
+        assert_eq!(
+            next_leap_end(utc(2015, 6, 30, 23, 59, 59)),
+            utc(2015, 7, 1, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn only_a_majority_announces_a_leap_second() {
+        use LeapIndicator::*;
+
+        assert_eq!(announced_leap(&[]), NoWarning);
+        assert_eq!(
+            announced_leap(&[AddSecond, AddSecond, NoWarning]),
+            AddSecond
+        );
+        assert_eq!(announced_leap(&[AddSecond, NoWarning]), NoWarning);
+        assert_eq!(
+            announced_leap(&[DeleteSecond, DeleteSecond, AddSecond]),
+            DeleteSecond
+        );
+    }
+}
@@ -1,213 +1,327 @@
-#[cfg(windows)]
-use kernel32;
-
-#[cfg(not(windows))]
-use libc;
-
-#[cfg(windows)]
-use winapi;
-
-use byteorder::{BigEndian, ReadBytesExt};
-use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone, Timelike, Utc};
-use clap::{App, Arg};
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use std::fmt;
 use std::mem::zeroed;
-use std::net::UdpSocket;
+
+#[cfg(unix)]
+mod daemon;
+mod datetime;
+mod error;
+mod leap;
+mod ntp;
+mod selection;
+mod server;
+mod servers;
+
+use datetime::{parse_time, Zone};
+use error::ClockError;
+use leap::{announced_leap, next_leap_end, LeapSecondTable};
+use ntp::LeapIndicator;
+use selection::{select, Candidate, Verdict};
+use server::{ServerConfig, LOCAL_STRATUM};
+use servers::{query_servers, read_servers_file, QueryOptions, ServerAddress};
+use std::path::Path;
 use std::time::Duration;
 
-const NTP_MESSAGE_LENGTH: usize = 48; // 12*4 bytes
-const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
-const LOCAL_ADDR: &'static str = "0.0.0.0:12300"; // 12300 is the default ntp port
+const DEFAULT_SERVERS: [&str; 6] = [
+    "time.nist.gov",
+    "time.apple.com",
+    "time.euro.apple.com",
+    "time.google.com",
+    "time2.google.com",
+    "time.windows.com",
+];
+
+fn check_time(
+    servers: &[ServerAddress],
+    options: QueryOptions,
+    leap_seconds: &LeapSecondTable,
+    verbose: bool,
+) -> Result<f64, std::io::Error> {
+    let mut replied = Vec::with_capacity(servers.len());
+    let mut candidates = Vec::with_capacity(servers.len());
+    let mut leaps = Vec::with_capacity(servers.len());
+
+    for server in query_servers(servers, options) {
+        print!("{} =>", server.server);
+
+        match &server.samples {
+            Ok(samples) => {
+                let time = server.best().expect("a server that replied has samples");
+                let reply = &time.reply;
+                let leap = match reply.leap {
+                    LeapIndicator::AddSecond | LeapIndicator::DeleteSecond => {
+                        format!(", leap {}", reply.leap)
+                    }
+                    _ => String::new(),
+                };
+                println!(
+                    " {}ms away from local system time (stratum {}, refid {}, {}/{} replies{})",
+                    time.offset(),
+                    reply.stratum,
+                    reply.reference_id.describe(reply.stratum),
+                    samples.len(),
+                    options.samples,
+                    leap
+                );
+                if verbose {
+                    println!("    {}", reply);
+                }
+                replied.push(server.server.clone());
+                candidates.push(Candidate::from_samples(time, samples));
+                leaps.push(reply.leap);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                println!(" ? [rejected reply: {}]", err)
+            }
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                println!(" ? [response took too long]")
+            }
+            Err(err) => println!(" ? [{}]", err),
+        };
+    }
 
-#[derive(Default, Debug, Clone, Copy)]
-struct NTPTimestamp {
-    seconds: u32,
-    fraction: u32,
-}
+    if candidates.is_empty() {
+        return Err(std::io::Error::other("no server replied"));
+    }
+    let selection = select(&candidates).ok_or_else(|| {
+        std::io::Error::other("no majority of the servers that replied agree on the time")
+    })?;
+
+    let (low, high) = selection.interval;
+    println!(
+        "the majority of servers puts the offset within [{:.3}ms, {:.3}ms]:",
+        low, high
+    );
+    for (server, verdict) in replied.iter().zip(&selection.verdicts) {
+        println!("  {} => {}", server, verdict);
+    }
+    println!("combined offset: {:.3}ms", selection.offset);
 
-struct NTPMessage {
-    data: [u8; NTP_MESSAGE_LENGTH],
-}
+    let accepted: Vec<LeapIndicator> = leaps
+        .iter()
+        .zip(&selection.verdicts)
+        .filter(|(_, verdict)| matches!(verdict, Verdict::Accepted { .. }))
+        .map(|(&leap, _)| leap)
+        .collect();
+    report_leap(announced_leap(&accepted), leap_seconds);
 
-#[derive(Debug)]
-struct NTPResult {
-    t1: DateTime<Utc>,
-    t2: DateTime<Utc>,
-    t3: DateTime<Utc>,
-    t4: DateTime<Utc>,
+    Ok(selection.offset)
 }
 
-impl NTPResult {
-    fn offset(&self) -> i64 {
-        let duration = (self.t2 - self.t1) + (self.t4 - self.t3);
-
-        duration.num_milliseconds() / 2
+/// Says when the leap second the servers announce is due, and whether the leap second
+/// table knows about it.
+fn report_leap(leap: LeapIndicator, leap_seconds: &LeapSecondTable) {
+    if leap == LeapIndicator::NoWarning {
+        return;
     }
 
-    fn delay(&self) -> i64 {
-        let duration = (self.t4 - self.t1) + (self.t3 - self.t2);
-
-        duration.num_milliseconds()
-    }
+    let end = next_leap_end(Utc::now());
+    let listed = if leap_seconds.lists_leap_at(end) {
+        "listed in the leap second table"
+    } else {
+        "not in the leap second table"
+    };
+    println!(
+        "the servers announce a leap second: {} before {} ({})",
+        leap,
+        end.to_rfc3339(),
+        listed
+    );
 }
 
-impl From<NTPTimestamp> for DateTime<Utc> {
-    fn from(ntp: NTPTimestamp) -> Self {
-        let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
-        let mut nanos = ntp.fraction as f64;
-        nanos *= 1e9;
-        nanos /= 2_f64.powi(32);
-
-        Utc.timestamp(secs, nanos as u32)
+/// The leap second table given with `--leap-file`, or else the system's or the bundled
+/// one. Warns when the table has expired, as it may miss a recently announced leap second.
+fn leap_seconds(args: &ArgMatches) -> LeapSecondTable {
+    let table = match args.value_of("leap-file") {
+        Some(path) => LeapSecondTable::load(Path::new(path)).unwrap_or_else(|err| {
+            usage_error(format!("Unable to read the leap second table: {}", err))
+        }),
+        None => LeapSecondTable::system_or_bundled(),
+    };
+
+    if let Some(expires) = table.expires().filter(|&expires| expires < Utc::now()) {
+        eprintln!(
+            "warning: the leap second table expired on {}, pass a newer one with --leap-file",
+            expires.date_naive()
+        );
     }
+    table
 }
 
-impl From<DateTime<Utc>> for NTPTimestamp {
-    fn from(utc: DateTime<Utc>) -> Self {
-        let secs = utc.timestamp() + NTP_TO_UNIX_SECONDS;
-        let mut fraction = utc.nanosecond() as f64;
-        fraction *= 2_f64.powi(32);
-        fraction /= 1e9;
-
-        NTPTimestamp {
-            seconds: secs as u32,
-            fraction: fraction as u32,
-        }
+/// The servers to query and how, from the arguments `check-ntp` and `daemon` share.
+fn ntp_options(args: &ArgMatches) -> (Vec<ServerAddress>, QueryOptions, ChronoDuration) {
+    let mut servers: Vec<ServerAddress> = vec![];
+    for server in args.values_of("server").into_iter().flatten() {
+        servers.push(server.parse().unwrap_or_else(|err| usage_error(err)));
     }
-}
-
-impl NTPMessage {
-    fn new() -> Self {
-        NTPMessage {
-            data: [0; NTP_MESSAGE_LENGTH],
-        }
+    if let Some(path) = args.value_of("servers-file") {
+        let listed = read_servers_file(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("Unable to read the servers file: {}", err);
+            std::process::exit(1);
+        });
+        servers.extend(listed);
     }
-
-    fn client() -> Self {
-        // two bits for leap indicator
-        // three for version
-        // three for mode
-        const VERSION: u8 = 0b00_011_000;
-        const MODE: u8 = 0b00_000_011;
-
-        let mut msg = NTPMessage::new();
-
-        msg.data[0] |= VERSION;
-        msg.data[0] |= MODE;
-
-        msg
+    if servers.is_empty() {
+        servers = DEFAULT_SERVERS.iter().map(|s| s.parse().unwrap()).collect();
     }
 
-    fn parse_timestamp(&self, i: usize) -> Result<NTPTimestamp, std::io::Error> {
-        let mut reader = &self.data[i..i + 8];
-        let seconds = reader.read_u32::<BigEndian>()?;
-        let fraction = reader.read_u32::<BigEndian>()?;
-
-        Ok(NTPTimestamp {
-            seconds: seconds,
-            fraction: fraction,
-        })
-    }
+    let options = QueryOptions {
+        samples: match args.value_of("samples").unwrap().parse() {
+            Ok(samples @ 1..) => samples,
+            _ => usage_error("--samples must be a positive number"),
+        },
+        timeout: match args.value_of("timeout").unwrap().parse() {
+            Ok(ms @ 1..) => Duration::from_millis(ms),
+            _ => usage_error("--timeout must be a positive number of milliseconds"),
+        },
+    };
+
+    let step_threshold = match args.value_of("step-threshold").unwrap().parse() {
+        Ok(ms @ 0..) => ChronoDuration::milliseconds(ms),
+        _ => usage_error("--step-threshold must be a number of milliseconds"),
+    };
+
+    (servers, options, step_threshold)
+}
 
-    // Receive
-    fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(32)
+#[cfg(unix)]
+fn run_daemon(args: &ArgMatches) {
+    let (servers, query, step_threshold) = ntp_options(args);
+
+    let poll = |name: &str| match args.value_of(name).unwrap().parse() {
+        Ok(poll @ 0..=17) => poll,
+        _ => usage_error(format!("--{} must be between 0 and 17", name)),
+    };
+    let (min_poll, max_poll) = (poll("min-poll"), poll("max-poll"));
+    if min_poll > max_poll {
+        usage_error("--min-poll can't be above --max-poll");
     }
 
-    // Transmit
-    fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(40)
+    let config = daemon::DaemonConfig {
+        servers,
+        query,
+        step_threshold,
+        min_poll,
+        max_poll,
+        drift_file: args.value_of("drift-file").unwrap().into(),
+        status_socket: args.value_of("status-socket").unwrap().into(),
+        leap_seconds: leap_seconds(args),
+        dry_run: args.is_present("dry-run"),
+    };
+
+    if let Err(err) = daemon::run(config) {
+        eprintln!("Unable to start the daemon: {}", err);
+        std::process::exit(1);
     }
 }
 
-fn weighted_mean(values: &[f64], weights: &[f64]) -> f64 {
-    let mut result = 0.0;
-    let mut sum_of_weights = 0.0;
+#[cfg(unix)]
+fn print_daemon_status(args: &ArgMatches) {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
 
-    for (v, w) in values.iter().zip(weights) {
-        result += v * w;
-        sum_of_weights += w;
-    }
+    let path = args.value_of("status-socket").unwrap();
+    let mut status = String::new();
 
-    result / sum_of_weights
+    match UnixStream::connect(path).and_then(|mut stream| stream.read_to_string(&mut status)) {
+        Ok(_) => print!("{}", status),
+        Err(err) => {
+            eprintln!("Unable to reach the daemon at {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn ntp_roundtrip(host: &str, port: u16) -> Result<NTPResult, std::io::Error> {
-    let destination = format!("{}:{}", host, port);
-    let timeout = Duration::from_secs(1);
-
-    let request = NTPMessage::client();
-    let mut response = NTPMessage::new();
-
-    let message = request.data;
-
-    let udp = UdpSocket::bind(LOCAL_ADDR)?;
-    udp.connect(&destination).expect("unable to connect");
-
-    let t1 = Utc::now();
-
-    udp.send(&message)?;
-    udp.set_read_timeout(Some(timeout))?;
-    udp.recv_from(&mut response.data)?;
+#[cfg(not(unix))]
+fn run_daemon(_args: &ArgMatches) {
+    usage_error("the daemon needs Unix sockets, which this platform doesn't have");
+}
 
-    let t4 = Utc::now();
-    let t2: DateTime<Utc> = response.rx_time().unwrap().into();
-    let t3: DateTime<Utc> = response.tx_time().unwrap().into();
+#[cfg(not(unix))]
+fn print_daemon_status(_args: &ArgMatches) {
+    usage_error("the daemon needs Unix sockets, which this platform doesn't have");
+}
 
-    Ok(NTPResult {
-        t1: t1,
-        t2: t2,
-        t3: t3,
-        t4: t4,
+/// The zone given with `--from` or `--to`, or `default` if there's none.
+fn zone_arg(args: &ArgMatches, name: &str, default: Zone) -> Zone {
+    args.value_of(name).map_or(default, |zone| {
+        zone.parse().unwrap_or_else(|err| usage_error(err))
     })
 }
 
-fn check_time() -> Result<f64, std::io::Error> {
-    const NTP_PORT: u16 = 123;
-
-    let servers = [
-        "time.nist.gov",
-        "time.apple.com",
-        "time.euro.apple.com",
-        "time.google.com",
-        "time2.google.com",
-        "time.windows.com",
-    ];
-
-    let mut times = Vec::with_capacity(servers.len());
-
-    for &server in servers.iter() {
-        print!("{} =>", server);
-
-        let calc = ntp_roundtrip(&server, NTP_PORT);
-
-        match calc {
-            Ok(time) => {
-                println!(" {}ms away from local system time", time.offset());
-                times.push(time);
-            }
-            Err(_) => {
-                println!(" ? [response took too long]")
-            }
-        };
+/// Prints `now` in each of `zones`, one per line, with the zone's name and the
+/// abbreviation in use there.
+fn print_world_clock(zones: &[Zone], now: DateTime<Utc>, std: &str, format: Option<&str>) {
+    let names: Vec<String> = zones.iter().map(Zone::to_string).collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0);
+
+    for (zone, name) in zones.iter().zip(&names) {
+        println!(
+            "{:width$}  {}  {}",
+            name,
+            zone.format(now, std, format)
+                .unwrap_or_else(|err| usage_error(err)),
+            zone.abbreviation(now),
+            width = width
+        );
     }
+}
 
-    let mut offsets = Vec::with_capacity(servers.len());
-    let mut offset_weights = Vec::with_capacity(servers.len());
+fn usage_error<T: std::fmt::Display>(message: T) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(2);
+}
 
-    for time in &times {
-        let offset = time.offset() as f64;
-        let delay = time.delay() as f64;
+/// How `check-ntp` brings the clock in line with the servers.
+#[derive(Debug, Clone, Copy)]
+enum Correction {
+    /// Speed the clock up or slow it down until it has made up the offset.
+    Slew(ChronoDuration),
+    /// Jump to the right time at once.
+    Step(ChronoDuration),
+}
 
-        let weight = 1_000_000.0 / (delay * delay);
-        if weight.is_finite() {
-            offsets.push(offset);
-            offset_weights.push(weight);
+impl Correction {
+    /// Slews offsets below `step_threshold`: slewing a large one would take hours.
+    fn for_offset(offset: ChronoDuration, step_threshold: ChronoDuration) -> Self {
+        if offset.num_microseconds().unwrap_or(i64::MAX).abs()
+            < step_threshold.num_microseconds().unwrap_or(i64::MAX)
+        {
+            Correction::Slew(offset)
+        } else {
+            Correction::Step(offset)
         }
     }
 
-    let avg_offset = weighted_mean(&offsets, &offset_weights);
+    fn apply(self) -> Result<(), ClockError> {
+        match self {
+            Correction::Slew(offset) => Clock::slew(offset).map(|_| ()),
+            Correction::Step(offset) => Clock::set(Utc::now() + offset),
+        }
+    }
+}
 
-    Ok(avg_offset)
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // kernels slew at 500ppm, half a millisecond per second
+            Correction::Slew(offset) => write!(
+                f,
+                "slew the clock by {:.3}ms over about {:.1}s",
+                offset.num_microseconds().unwrap_or(0) as f64 / 1e3,
+                offset.num_microseconds().unwrap_or(0).abs() as f64 / 500.0 / 1e3
+            ),
+            Correction::Step(offset) => write!(
+                f,
+                "step the clock by {:.3}ms",
+                offset.num_microseconds().unwrap_or(i64::MAX) as f64 / 1e3
+            ),
+        }
+    }
 }
 
 struct Clock;
@@ -218,12 +332,18 @@ impl Clock {
     }
 
     #[cfg(windows)]
-    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> () {
-        use chrono::{Datelike, Weekday};
+    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> Result<(), ClockError> {
+        use chrono::{Datelike, Timelike, Weekday};
         use kernel32::SetSystemTime;
         use winapi::{SYSTEMTIME, WORD};
 
         let t = t.with_timezone(&Local);
+        if !(1601..=30827).contains(&t.year()) {
+            return Err(ClockError::InvalidTime(format!(
+                "{} is outside the years Windows can represent",
+                t.to_rfc3339()
+            )));
+        }
 
         let mut systime: SYSTEMTIME = unsafe { zeroed() };
 
@@ -237,12 +357,9 @@ impl Clock {
             Weekday::Sun => 0,
         };
 
-        let mut ns = t.nanosecond();
-        let is_leap_second = ns > 1_000_000_000;
-
-        if is_leap_second {
-            ns -= 1_000_000_000;
-        }
+        // chrono puts a leap second's 23:59:60 at 23:59:59 with over a second of
+        // nanoseconds; Windows has no 60th second, so it's 23:59:59 again
+        let ns = t.nanosecond() % 1_000_000_000;
 
         systime.wYear = t.year() as WORD;
         systime.wMonth = t.month() as WORD;
@@ -255,27 +372,174 @@ impl Clock {
 
         let systime_ptr = &systime as *const SYSTEMTIME;
 
-        unsafe {
-            SetSystemTime(systime_ptr);
+        let succeeded = unsafe { SetSystemTime(systime_ptr) };
+
+        if succeeded != 0 {
+            Ok(())
+        } else {
+            Err(ClockError::last_os_error())
         }
     }
 
     #[cfg(not(windows))]
-    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> () {
+    fn set<Tz: TimeZone>(t: DateTime<Tz>) -> Result<(), ClockError> {
         use libc::{settimeofday, timezone};
         use libc::{suseconds_t, time_t, timeval};
 
         let t = t.with_timezone(&Local);
         let mut u: timeval = unsafe { zeroed() };
 
-        u.tv_sec = t.timestamp() as time_t;
-        u.tv_usec = t.timestamp_subsec_micros() as suseconds_t;
+        // a 32-bit time_t ends in 2038
+        u.tv_sec = time_t::try_from(t.timestamp()).map_err(|_| {
+            ClockError::InvalidTime(format!("{} is out of this system's range", t.to_rfc3339()))
+        })?;
+        // POSIX time repeats 23:59:59 during a leap second
+        u.tv_usec = (t.timestamp_subsec_micros() % 1_000_000) as suseconds_t;
 
-        unsafe {
+        let result = unsafe {
             let mock_tz: *const timezone = std::ptr::null();
-            settimeofday(&u as *const timeval, mock_tz);
+            settimeofday(&u as *const timeval, mock_tz)
+        };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(ClockError::last_os_error())
+        }
+    }
+
+    /// Finds out whether this process may set the clock, without setting it.
+    #[cfg(target_os = "linux")]
+    fn check_privileges() -> Result<(), ClockError> {
+        const CAP_SYS_TIME: u32 = 25;
+
+        let status = std::fs::read_to_string("/proc/self/status").map_err(ClockError::Os)?;
+        let effective = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .ok_or(ClockError::Unsupported(
+                "reading the process's capabilities",
+            ))?;
+
+        if effective & (1 << CAP_SYS_TIME) != 0 {
+            Ok(())
+        } else {
+            Err(ClockError::PermissionDenied)
+        }
+    }
+
+    /// Finds out whether this process may set the clock, without setting it.
+    #[cfg(all(not(windows), not(target_os = "linux")))]
+    fn check_privileges() -> Result<(), ClockError> {
+        if unsafe { libc::geteuid() } == 0 {
+            Ok(())
+        } else {
+            Err(ClockError::PermissionDenied)
+        }
+    }
+
+    #[cfg(windows)]
+    fn check_privileges() -> Result<(), ClockError> {
+        Err(ClockError::Unsupported("checking privileges"))
+    }
+
+    /// Asks the kernel to make up `offset` gradually, by running the clock slightly
+    /// fast or slow. Time never jumps, nor runs backwards. Replaces any slew in progress.
+    ///
+    /// # Returns
+    ///
+    /// What the replaced slew still had to make up.
+    #[cfg(windows)]
+    fn slew(_offset: ChronoDuration) -> Result<ChronoDuration, ClockError> {
+        Err(ClockError::Unsupported("slewing the clock"))
+    }
+
+    /// Asks the kernel to make up `offset` gradually, by running the clock slightly
+    /// fast or slow. Time never jumps, nor runs backwards. Replaces any slew in progress.
+    ///
+    /// # Returns
+    ///
+    /// What the replaced slew still had to make up.
+    #[cfg(not(windows))]
+    fn slew(offset: ChronoDuration) -> Result<ChronoDuration, ClockError> {
+        use libc::{adjtime, suseconds_t, time_t, timeval};
+
+        let micros = offset.num_microseconds().unwrap_or(i64::MAX);
+        let delta = timeval {
+            tv_sec: (micros / 1_000_000) as time_t,
+            tv_usec: (micros % 1_000_000) as suseconds_t,
+        };
+
+        let mut pending: timeval = unsafe { zeroed() };
+        let result = unsafe { adjtime(&delta as *const timeval, &mut pending as *mut timeval) };
+
+        if result == 0 {
+            let pending = pending.tv_sec as i64 * 1_000_000 + pending.tv_usec as i64;
+            Ok(ChronoDuration::microseconds(pending))
+        } else {
+            Err(ClockError::last_os_error())
+        }
+    }
+
+    /// Corrects the frequency of the clock's oscillator by `ppm` parts per million;
+    /// positive values make it run faster. Replaces the previous correction, and
+    /// stays in effect until the next one or a reboot.
+    #[cfg(target_os = "linux")]
+    fn set_frequency(ppm: f64) -> Result<(), ClockError> {
+        use libc::{adjtimex, timex, ADJ_FREQUENCY};
+
+        let mut tx: timex = unsafe { zeroed() };
+        tx.modes = ADJ_FREQUENCY;
+        tx.freq = (ppm * 65_536.0) as libc::c_long; // in ppm with a 16-bit fraction
+
+        let result = unsafe { adjtimex(&mut tx as *mut timex) };
+
+        if result == -1 {
+            Err(ClockError::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Arms the kernel to insert or delete a second at the next midnight UTC, as
+    /// `leap` says, or disarms it for `NoWarning`.
+    #[cfg(target_os = "linux")]
+    fn set_leap(leap: LeapIndicator) -> Result<(), ClockError> {
+        use libc::{adjtimex, timex, ADJ_STATUS, STA_DEL, STA_INS};
+
+        // the other status bits have to be written back as they are
+        let mut tx: timex = unsafe { zeroed() };
+        if unsafe { adjtimex(&mut tx as *mut timex) } == -1 {
+            return Err(ClockError::last_os_error());
+        }
+
+        tx.modes = ADJ_STATUS;
+        tx.status &= !(STA_INS | STA_DEL);
+        match leap {
+            LeapIndicator::AddSecond => tx.status |= STA_INS,
+            LeapIndicator::DeleteSecond => tx.status |= STA_DEL,
+            _ => {}
+        }
+
+        let result = unsafe { adjtimex(&mut tx as *mut timex) };
+
+        if result == -1 {
+            Err(ClockError::last_os_error())
+        } else {
+            Ok(())
         }
     }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_leap(_leap: LeapIndicator) -> Result<(), ClockError> {
+        Err(ClockError::Unsupported("scheduling leap seconds"))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_frequency(_ppm: f64) -> Result<(), ClockError> {
+        Err(ClockError::Unsupported("correcting the clock's frequency"))
+    }
 }
 
 fn main() {
@@ -286,15 +550,28 @@ fn main() {
     let app = App::new("clock")
         .version("0.1.2")
         .about(about.as_str())
+        .setting(AppSettings::AllowLeadingHyphen) // for relative times like -2h30m
         .after_help(
-            "Note: Note: UNIX timestamps are parsed as whole 
-        seconds since 1st January 1970 0:00:00 UTC. 
-        For more accuracy, use another format.",
+            "Note: UNIX timestamps are seconds since 1st January 1970 0:00:00 UTC,
+        and may have up to nine decimals. <datetime> may also be relative
+        to the current time, like +5m or -2h30m (units: w, d, h, m, s, ms).
+
+Examples: clock convert 2030-01-01T09:00:00Z --to Asia/Tokyo
+          clock world Africa/Lagos Asia/Tokyo",
         )
         .arg(
             Arg::with_name("action")
                 .takes_value(true)
-                .possible_values(&["get", "set", "check-ntp"])
+                .possible_values(&[
+                    "get",
+                    "set",
+                    "convert",
+                    "world",
+                    "check-ntp",
+                    "serve",
+                    "daemon",
+                    "status",
+                ])
                 .default_value("get"),
         )
         .arg(
@@ -305,9 +582,154 @@ fn main() {
                 .possible_values(&["rfc2822", "rfc3339", "timestamp"])
                 .default_value("rfc3339"),
         )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .help("A strftime pattern, like \"%Y-%m-%d %H:%M:%S\", to show the time with, or to read <datetime> with. Overrides --use-std. With convert, it's shown with --to-format if that's given."),
+        )
+        .arg(
+            Arg::with_name("tz")
+                .long("tz")
+                .takes_value(true)
+                .default_value("local")
+                .help("The time zone to show the time in, and to read a <datetime> without an offset in: \"local\", a name like Europe/Paris, or an offset like +05:30."),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .takes_value(true)
+                .possible_values(&["utc", "tai"])
+                .default_value("utc")
+                .help("With get, the time scale to show the time in. TAI runs ahead of UTC by the leap seconds inserted since 1972."),
+        )
+        .arg(
+            Arg::with_name("leap-file")
+                .long("leap-file")
+                .takes_value(true)
+                .help("A leap second table in the format of leap-seconds.list, like /usr/share/zoneinfo/leap-seconds.list, to use instead of the system's or the bundled one, with get --scale tai, check-ntp and daemon."),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .help("With convert, the time zone to read a <datetime> without an offset in [default: --tz]."),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .help("With convert, the time zone to show the time in [default: --tz]."),
+        )
+        .arg(
+            Arg::with_name("to-format")
+                .long("to-format")
+                .takes_value(true)
+                .help("With convert, how to show the time: rfc2822, rfc3339, timestamp or a strftime pattern [default: as it was read]."),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("With check-ntp, query this HOST[:PORT] instead of the default servers. Repeatable."),
+        )
+        .arg(
+            Arg::with_name("servers-file")
+                .long("servers-file")
+                .takes_value(true)
+                .help("With check-ntp, also query the servers listed in this file, one per line."),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .long("samples")
+                .takes_value(true)
+                .default_value("4")
+                .help("With check-ntp, the number of requests sent to each server."),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .default_value("1000")
+                .help("With check-ntp, how long to wait for each reply, in milliseconds."),
+        )
+        .arg(
+            Arg::with_name("step-threshold")
+                .long("step-threshold")
+                .takes_value(true)
+                .default_value("128")
+                .help("With check-ntp, offsets below this many milliseconds are slewed, larger ones stepped."),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("With set, only check that this process may set the time. <datetime> is optional."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("With set and check-ntp, print what would be done to the clock without doing it."),
+        )
+        .arg(
+            Arg::with_name("min-poll")
+                .long("min-poll")
+                .takes_value(true)
+                .default_value("6")
+                .help("With daemon, the shortest interval between polls, as a power of two seconds."),
+        )
+        .arg(
+            Arg::with_name("max-poll")
+                .long("max-poll")
+                .takes_value(true)
+                .default_value("10")
+                .help("With daemon, the longest interval between polls, as a power of two seconds."),
+        )
+        .arg(
+            Arg::with_name("drift-file")
+                .long("drift-file")
+                .takes_value(true)
+                .default_value("/var/lib/clock/drift")
+                .help("With daemon, where the clock's frequency error is kept across restarts."),
+        )
+        .arg(
+            Arg::with_name("status-socket")
+                .long("status-socket")
+                .takes_value(true)
+                .default_value("/run/clock.sock")
+                .help("With daemon and status, the Unix socket the daemon reports its status on."),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .default_value("123")
+                .help("With serve, the UDP port to answer on."),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .takes_value(true)
+                .help("With serve, the HOST[:PORT] whose time is served, corrected for the local clock's offset from it."),
+        )
+        .arg(
+            Arg::with_name("stratum")
+                .long("stratum")
+                .takes_value(true)
+                .help("With serve and no upstream, the stratum to announce [default: 10]."),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("With check-ntp, print every header field of each server's reply."),
+        )
         .arg(
             Arg::with_name("datetime")
-                .help("When <action> is 'set', apply <datetime>. Otherwise, ignore."),
+                .multiple(true)
+                .help("With set, the time to apply. With convert, the time to convert. With world, the time zones to show the current time in."),
         );
 
     let args = app.get_matches();
@@ -315,46 +737,141 @@ fn main() {
     let action = args.value_of("action").unwrap();
     let std = args.value_of("std").unwrap();
 
-    if action == "set" {
-        let t_ = args.value_of("datetime").unwrap();
-
-        let parser = match std {
-            "rfc2822" => DateTime::parse_from_rfc2822,
-            "rfc3339" => DateTime::parse_from_rfc3339,
-            _ => unimplemented!(),
-        };
+    let format = args.value_of("format");
+    let zone: Zone = args
+        .value_of("tz")
+        .unwrap()
+        .parse()
+        .unwrap_or_else(|err| usage_error(err));
 
-        let err_msg = format!("Unable to parse {} according to {}", t_, std);
-        let t = parser(t_).expect(&err_msg);
+    if action != "world" && args.occurrences_of("datetime") > 1 {
+        usage_error(format!("{} takes a single <datetime>", action));
+    }
 
-        Clock::set(t);
+    if action == "set" {
+        let t = args.value_of("datetime").map(|t_| {
+            parse_time(t_, std, format, &zone, Utc::now())
+                .unwrap_or_else(|err| usage_error(format!("Unable to parse the time: {}", err)))
+        });
+
+        if args.is_present("check") {
+            if let Err(err) = Clock::check_privileges() {
+                eprintln!("Unable to set the time: {}", err);
+                std::process::exit(err.exit_code());
+            }
+            println!("This process may set the time");
+        } else {
+            let t = t.unwrap_or_else(|| usage_error("set needs a <datetime>"));
+
+            if args.is_present("dry-run") {
+                println!("Would set the clock to {}", t.to_rfc3339());
+            } else if let Err(err) = Clock::set(t) {
+                eprintln!("Unable to set the time: {}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+    } else if action == "convert" {
+        let from = zone_arg(&args, "from", zone);
+        let to = zone_arg(&args, "to", zone);
+
+        let input = args
+            .value_of("datetime")
+            .unwrap_or_else(|| usage_error("convert needs a <datetime>"));
+        let t = parse_time(input, std, format, &from, Utc::now())
+            .unwrap_or_else(|err| usage_error(format!("Unable to parse the time: {}", err)));
+
+        let (to_std, to_format) = match args.value_of("to-format") {
+            None => (std, format),
+            Some(to_std @ ("rfc2822" | "rfc3339" | "timestamp")) => (to_std, None),
+            Some(pattern) => (std, Some(pattern)),
+        };
+        let rendered = to
+            .format(t, to_std, to_format)
+            .unwrap_or_else(|err| usage_error(err));
+        println!("{}", rendered);
+        return;
+    } else if action == "world" {
+        let zones: Vec<Zone> = args
+            .values_of("datetime")
+            .unwrap_or_else(|| usage_error("world needs at least one time zone"))
+            .map(|name| name.parse().unwrap_or_else(|err| usage_error(err)))
+            .collect();
+
+        print_world_clock(&zones, Clock::get().with_timezone(&Utc), std, format);
+        return;
     } else if action == "check-ntp" {
-        let offset = check_time().unwrap() as isize;
-
-        let adjust_ms_ = offset.signum() * offset.abs().min(200) / 5;
-        let adjust_ms = ChronoDuration::microseconds(adjust_ms_ as i64);
+        let (servers, options, step_threshold) = ntp_options(&args);
+
+        let offset = match check_time(
+            &servers,
+            options,
+            &leap_seconds(&args),
+            args.is_present("verbose"),
+        ) {
+            Ok(offset) => ChronoDuration::microseconds((offset * 1e3) as i64),
+            Err(err) => {
+                eprintln!("Not adjusting the time: {}", err);
+                std::process::exit(1);
+            }
+        };
 
-        let now: DateTime<Utc> = Utc::now() + adjust_ms;
+        let correction = Correction::for_offset(offset, step_threshold);
+        if args.is_present("dry-run") {
+            println!("Would {}", correction);
+        } else {
+            println!("Going to {}", correction);
+            if let Err(err) = correction.apply() {
+                eprintln!("Unable to adjust the time: {}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+    } else if action == "daemon" {
+        run_daemon(&args);
+    } else if action == "status" {
+        print_daemon_status(&args);
+    } else if action == "serve" {
+        let port = args.value_of("port").unwrap();
+        let stratum = args
+            .value_of("stratum")
+            .map_or(Ok(LOCAL_STRATUM), str::parse);
+
+        let config = match (port.parse(), stratum) {
+            (Ok(port), Ok(stratum @ 1..=15)) => ServerConfig {
+                port,
+                stratum,
+                upstream: args
+                    .value_of("upstream")
+                    .map(|upstream| upstream.parse().unwrap_or_else(|err| usage_error(err))),
+            },
+            _ => usage_error("--port must be a port number and --stratum between 1 and 15"),
+        };
 
-        Clock::set(now);
+        if let Err(err) = server::serve(&config) {
+            eprintln!("Unable to serve NTP on port {}: {}", config.port, err);
+            std::process::exit(1);
+        }
     }
 
-    let maybe_error = std::io::Error::last_os_error();
-    let os_error_code = &maybe_error.raw_os_error();
+    let now = Clock::get().with_timezone(&Utc);
 
-    // matching on integers instead of enum, skips type safety and not fit for production
-    match os_error_code {
-        Some(0) => (),
-        Some(_) => eprintln!("Unable to set the time: {:?}", maybe_error),
-        None => (),
+    if args.value_of("scale") == Some("tai") {
+        if args.occurrences_of("tz") > 0 {
+            usage_error("TAI has no time zones: --tz can't be used with --scale tai");
+        }
+        let tai = leap_seconds(&args)
+            .to_tai(now)
+            .unwrap_or_else(|| usage_error("TAI - UTC is only known since 1972"));
+        let rendered =
+            datetime::format_tai(tai, std, format).unwrap_or_else(|err| usage_error(err));
+        println!("{}", rendered);
+        return;
     }
 
-    let now = Clock::get();
-
-    match std {
-        "timestamp" => println!("{}", now.timestamp()),
-        "rfc2822" => print!("{}", now.to_rfc2822()),
-        "rfc3339" => print!("{}", now.to_rfc3339()),
-        _ => unreachable!(),
+    let rendered = zone
+        .format(now, std, format)
+        .unwrap_or_else(|err| usage_error(err));
+    match (std, format) {
+        ("timestamp", None) => println!("{}", rendered),
+        _ => print!("{}", rendered),
     }
 }
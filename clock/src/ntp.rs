@@ -0,0 +1,550 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, TimeZone, Timelike, Utc};
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub const NTP_MESSAGE_LENGTH: usize = 48; // 12*4 bytes
+pub const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
+pub const NTP_VERSION: u8 = 3;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NTPTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+/// The 32-bit "short" format used for root delay and root dispersion: 16.16 fixed-point seconds.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NTPShort {
+    pub seconds: u16,
+    pub fraction: u16,
+}
+
+impl NTPShort {
+    pub fn as_secs_f64(&self) -> f64 {
+        self.seconds as f64 + self.fraction as f64 / 65_536.0
+    }
+
+    /// Saturates at the largest representable value, about 18 hours.
+    pub fn from_secs_f64(secs: f64) -> Self {
+        let fixed = (secs.max(0.0) * 65_536.0).min(u32::MAX as f64) as u32;
+        NTPShort {
+            seconds: (fixed >> 16) as u16,
+            fraction: fixed as u16,
+        }
+    }
+}
+
+/// Warns of a leap second to be inserted or deleted at the end of the current day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeapIndicator {
+    NoWarning,
+    AddSecond,    // last minute of the day has 61 seconds
+    DeleteSecond, // last minute of the day has 59 seconds
+    Unsynchronized,
+}
+
+impl LeapIndicator {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => LeapIndicator::NoWarning,
+            1 => LeapIndicator::AddSecond,
+            2 => LeapIndicator::DeleteSecond,
+            _ => LeapIndicator::Unsynchronized,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            LeapIndicator::NoWarning => 0,
+            LeapIndicator::AddSecond => 1,
+            LeapIndicator::DeleteSecond => 2,
+            LeapIndicator::Unsynchronized => 3,
+        }
+    }
+}
+
+impl fmt::Display for LeapIndicator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeapIndicator::NoWarning => write!(f, "none"),
+            LeapIndicator::AddSecond => write!(f, "insert second"),
+            LeapIndicator::DeleteSecond => write!(f, "delete second"),
+            LeapIndicator::Unsynchronized => write!(f, "unsynchronized"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Reserved,
+    SymmetricActive,
+    SymmetricPassive,
+    Client,
+    Server,
+    Broadcast,
+    Control,
+    Private,
+}
+
+impl Mode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0 => Mode::Reserved,
+            1 => Mode::SymmetricActive,
+            2 => Mode::SymmetricPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::Control,
+            _ => Mode::Private,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            Mode::Reserved => 0,
+            Mode::SymmetricActive => 1,
+            Mode::SymmetricPassive => 2,
+            Mode::Client => 3,
+            Mode::Server => 4,
+            Mode::Broadcast => 5,
+            Mode::Control => 6,
+            Mode::Private => 7,
+        }
+    }
+}
+
+/// Identifies the server's time source. Its meaning depends on the stratum: an ASCII
+/// code (a reference clock like "GPS", or a kiss code at stratum 0), otherwise the
+/// IPv4 address of the upstream server, or a hash of its IPv6 address.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReferenceId(pub [u8; 4]);
+
+impl ReferenceId {
+    pub fn describe(&self, stratum: u8) -> String {
+        match stratum {
+            0 | 1 => self
+                .0
+                .iter()
+                .take_while(|&&b| b != 0)
+                .map(|&b| b as char)
+                .collect(),
+            _ => Ipv4Addr::from(self.0).to_string(),
+        }
+    }
+
+    /// A reference clock's code, like "GPS" or "LOCL", padded with zeros.
+    pub fn code(code: &str) -> Self {
+        let mut id = [0; 4];
+        for (byte, &c) in id.iter_mut().zip(code.as_bytes()) {
+            *byte = c;
+        }
+        ReferenceId(id)
+    }
+}
+
+impl From<IpAddr> for ReferenceId {
+    /// The upstream server's IPv4 address, or the first four bytes of the MD5 hash of
+    /// its IPv6 address.
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(v4) => ReferenceId(v4.octets()),
+            IpAddr::V6(v6) => {
+                let digest = md5::compute(v6.octets());
+                ReferenceId([digest[0], digest[1], digest[2], digest[3]])
+            }
+        }
+    }
+}
+
+/// An NTP packet header, as defined by RFC 5905. Extension fields and the
+/// authenticator are not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NTPPacket {
+    pub leap: LeapIndicator,
+    pub version: u8,
+    pub mode: Mode,
+    pub stratum: u8,
+    pub poll: i8,      // log2 of the maximum interval between messages, in seconds
+    pub precision: i8, // log2 of the precision of the server's clock, in seconds
+    pub root_delay: NTPShort,
+    pub root_dispersion: NTPShort,
+    pub reference_id: ReferenceId,
+    pub reference_time: NTPTimestamp, // when the server's clock was last set
+    pub originate_time: NTPTimestamp, // the client's transmit time, echoed back
+    pub receive_time: NTPTimestamp,
+    pub transmit_time: NTPTimestamp,
+}
+
+#[derive(Debug, Clone)]
+pub struct NTPResult {
+    pub t1: DateTime<Utc>,
+    pub t2: DateTime<Utc>,
+    pub t3: DateTime<Utc>,
+    pub t4: DateTime<Utc>,
+    pub server: SocketAddr,
+    pub reply: NTPPacket,
+}
+
+impl NTPResult {
+    /// How far the server's clock is ahead of ours: the mean of the two one-way
+    /// differences, so that a symmetric network delay cancels out.
+    pub fn offset(&self) -> i64 {
+        let duration = (self.t2 - self.t1) + (self.t3 - self.t4);
+
+        duration.num_milliseconds() / 2
+    }
+
+    /// The round trip, minus the time the server took to answer.
+    pub fn delay(&self) -> i64 {
+        let duration = (self.t4 - self.t1) - (self.t3 - self.t2);
+
+        duration.num_milliseconds()
+    }
+
+    /// `offset`, without rounding to whole milliseconds.
+    pub fn offset_ms(&self) -> f64 {
+        let duration = (self.t2 - self.t1) + (self.t3 - self.t4);
+
+        duration.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 2e6
+    }
+
+    /// `delay`, without rounding to whole milliseconds.
+    pub fn delay_ms(&self) -> f64 {
+        let duration = (self.t4 - self.t1) - (self.t3 - self.t2);
+
+        duration.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e6
+    }
+}
+
+impl From<NTPTimestamp> for DateTime<Utc> {
+    fn from(ntp: NTPTimestamp) -> Self {
+        let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
+        let mut nanos = ntp.fraction as f64;
+        nanos *= 1e9;
+        nanos /= 2_f64.powi(32);
+
+        Utc.timestamp_opt(secs, nanos as u32)
+            .single()
+            .expect("fraction is below one second")
+    }
+}
+
+impl From<DateTime<Utc>> for NTPTimestamp {
+    fn from(utc: DateTime<Utc>) -> Self {
+        let secs = utc.timestamp() + NTP_TO_UNIX_SECONDS;
+        let mut fraction = utc.nanosecond() as f64;
+        fraction *= 2_f64.powi(32);
+        fraction /= 1e9;
+
+        NTPTimestamp {
+            seconds: secs as u32,
+            fraction: fraction as u32,
+        }
+    }
+}
+
+impl NTPPacket {
+    pub fn new(mode: Mode) -> Self {
+        NTPPacket {
+            leap: LeapIndicator::NoWarning,
+            version: NTP_VERSION,
+            mode,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: NTPShort::default(),
+            root_dispersion: NTPShort::default(),
+            reference_id: ReferenceId::default(),
+            reference_time: NTPTimestamp::default(),
+            originate_time: NTPTimestamp::default(),
+            receive_time: NTPTimestamp::default(),
+            transmit_time: NTPTimestamp::default(),
+        }
+    }
+
+    /// A client request. The transmit timestamp is echoed back by the server as
+    /// the originate timestamp, which ties the reply to this request.
+    pub fn client(transmit_time: NTPTimestamp) -> Self {
+        NTPPacket {
+            transmit_time,
+            ..NTPPacket::new(Mode::Client)
+        }
+    }
+
+    pub fn encode(&self) -> [u8; NTP_MESSAGE_LENGTH] {
+        let mut data = [0; NTP_MESSAGE_LENGTH];
+
+        // two bits for leap indicator
+        // three for version
+        // three for mode
+        data[0] = self.leap.bits() << 6 | (self.version & 0b111) << 3 | self.mode.bits();
+        data[1] = self.stratum;
+        data[2] = self.poll as u8;
+        data[3] = self.precision as u8;
+
+        for (i, short) in [(4, self.root_delay), (8, self.root_dispersion)] {
+            data[i..i + 2].copy_from_slice(&short.seconds.to_be_bytes());
+            data[i + 2..i + 4].copy_from_slice(&short.fraction.to_be_bytes());
+        }
+
+        data[12..16].copy_from_slice(&self.reference_id.0);
+
+        let timestamps = [
+            (16, self.reference_time),
+            (24, self.originate_time),
+            (32, self.receive_time),
+            (40, self.transmit_time),
+        ];
+        for (i, timestamp) in timestamps {
+            data[i..i + 4].copy_from_slice(&timestamp.seconds.to_be_bytes());
+            data[i + 4..i + 8].copy_from_slice(&timestamp.fraction.to_be_bytes());
+        }
+
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < NTP_MESSAGE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NTP packet too short",
+            ));
+        }
+
+        let mut reader = &data[..NTP_MESSAGE_LENGTH];
+        let first = reader.read_u8()?;
+        let stratum = reader.read_u8()?;
+        let poll = reader.read_i8()?;
+        let precision = reader.read_i8()?;
+        let root_delay = NTPShort {
+            seconds: reader.read_u16::<BigEndian>()?,
+            fraction: reader.read_u16::<BigEndian>()?,
+        };
+        let root_dispersion = NTPShort {
+            seconds: reader.read_u16::<BigEndian>()?,
+            fraction: reader.read_u16::<BigEndian>()?,
+        };
+        let mut reference_id = [0; 4];
+        reader.read_exact(&mut reference_id)?;
+
+        let mut timestamp = || -> Result<NTPTimestamp, io::Error> {
+            let seconds = reader.read_u32::<BigEndian>()?;
+            let fraction = reader.read_u32::<BigEndian>()?;
+            Ok(NTPTimestamp { seconds, fraction })
+        };
+
+        Ok(NTPPacket {
+            leap: LeapIndicator::from_bits(first >> 6),
+            version: (first >> 3) & 0b111,
+            mode: Mode::from_bits(first),
+            stratum,
+            poll,
+            precision,
+            root_delay,
+            root_dispersion,
+            reference_id: ReferenceId(reference_id),
+            reference_time: timestamp()?,
+            originate_time: timestamp()?,
+            receive_time: timestamp()?,
+            transmit_time: timestamp()?,
+        })
+    }
+}
+
+impl fmt::Display for NTPPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reference_time: DateTime<Utc> = self.reference_time.into();
+        write!(
+            f,
+            "v{} {:?}, stratum {}, refid {}, leap {}, poll 2^{}s, precision 2^{}s, root delay {:.3}ms, root dispersion {:.3}ms, reference time {}",
+            self.version,
+            self.mode,
+            self.stratum,
+            self.reference_id.describe(self.stratum),
+            self.leap,
+            self.poll,
+            self.precision,
+            self.root_delay.as_secs_f64() * 1e3,
+            self.root_dispersion.as_secs_f64() * 1e3,
+            reference_time.to_rfc3339(),
+        )
+    }
+}
+
+fn invalid_reply(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Checks that `reply` is a server's answer to `request` that can be used for timing.
+fn check_reply(request: &NTPPacket, reply: &NTPPacket) -> Result<(), io::Error> {
+    // a reply to someone else's request, or a forged one, doesn't echo our transmit time
+    if reply.originate_time != request.transmit_time {
+        return Err(invalid_reply(format!(
+            "originate timestamp {:?} doesn't match our transmit timestamp {:?}",
+            reply.originate_time, request.transmit_time
+        )));
+    }
+    if reply.mode != Mode::Server {
+        return Err(invalid_reply(format!(
+            "expected a server reply, got mode {:?}",
+            reply.mode
+        )));
+    }
+    if reply.stratum == 0 {
+        let code = reply.reference_id.describe(0);
+        return Err(invalid_reply(format!("kiss-o'-death {}", code)));
+    }
+    Ok(())
+}
+
+/// Sends one client request to `server` and waits up to `timeout` for its reply.
+///
+/// Every call binds a socket of its own to an ephemeral port, so any number of
+/// queries can run at once.
+pub fn ntp_roundtrip(server: SocketAddr, timeout: Duration) -> Result<NTPResult, io::Error> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(local)?;
+    udp.connect(server)?;
+
+    let t1 = Utc::now();
+    let request = NTPPacket::client(t1.into());
+
+    udp.send(&request.encode())?;
+    udp.set_read_timeout(Some(timeout))?;
+
+    let mut response = [0; NTP_MESSAGE_LENGTH];
+    let len = udp.recv(&mut response)?;
+
+    let t4 = Utc::now();
+    let reply = NTPPacket::decode(&response[..len])?;
+    check_reply(&request, &reply)?;
+
+    let t2: DateTime<Utc> = reply.receive_time.into();
+    let t3: DateTime<Utc> = reply.transmit_time.into();
+
+    Ok(NTPResult {
+        t1,
+        t2,
+        t3,
+        t4,
+        server,
+        reply,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply_to(request: &NTPPacket) -> NTPPacket {
+        NTPPacket {
+            stratum: 2,
+            originate_time: request.transmit_time,
+            ..NTPPacket::new(Mode::Server)
+        }
+    }
+
+    #[test]
+    fn packets_survive_encoding() {
+        let packet = NTPPacket {
+            leap: LeapIndicator::DeleteSecond,
+            version: 4,
+            mode: Mode::Server,
+            stratum: 1,
+            poll: 6,
+            precision: -20,
+            root_delay: NTPShort::from_secs_f64(0.25),
+            root_dispersion: NTPShort {
+                seconds: 1,
+                fraction: 0x8000,
+            },
+            reference_id: ReferenceId::code("GPS"),
+            reference_time: NTPTimestamp {
+                seconds: 3_900_000_000,
+                fraction: 1,
+            },
+            originate_time: NTPTimestamp {
+                seconds: 3_900_000_001,
+                fraction: u32::MAX,
+            },
+            receive_time: NTPTimestamp {
+                seconds: 3_900_000_002,
+                fraction: 1 << 31,
+            },
+            transmit_time: NTPTimestamp {
+                seconds: u32::MAX,
+                fraction: 0,
+            },
+        };
+
+        let encoded = packet.encode();
+        assert_eq!(encoded[0], 0b10_100_100);
+        assert_eq!(encoded[3], -20i8 as u8);
+        assert_eq!(NTPPacket::decode(&encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn short_packets_are_rejected() {
+        let encoded = NTPPacket::new(Mode::Server).encode();
+        assert!(NTPPacket::decode(&encoded[..NTP_MESSAGE_LENGTH - 1]).is_err());
+    }
+
+    #[test]
+    fn timestamps_convert_to_and_from_utc() {
+        let t = Utc.with_ymd_and_hms(2024, 2, 29, 12, 30, 15).unwrap()
+            + chrono::Duration::milliseconds(250);
+        let ntp = NTPTimestamp::from(t);
+        assert_eq!(ntp.seconds as i64, t.timestamp() + NTP_TO_UNIX_SECONDS);
+        assert_eq!(ntp.fraction, 1 << 30);
+        assert_eq!(DateTime::<Utc>::from(ntp), t);
+    }
+
+    #[test]
+    fn replies_must_echo_the_transmit_time() {
+        let request = NTPPacket::client(NTPTimestamp {
+            seconds: 3_900_000_000,
+            fraction: 12_345,
+        });
+        assert!(check_reply(&request, &reply_to(&request)).is_ok());
+
+        let stale = NTPPacket {
+            originate_time: NTPTimestamp {
+                seconds: 3_900_000_000,
+                fraction: 12_346,
+            },
+            ..reply_to(&request)
+        };
+        let err = check_reply(&request, &stale).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("originate timestamp"), "{}", err);
+    }
+
+    #[test]
+    fn only_usable_server_replies_are_accepted() {
+        let request = NTPPacket::client(NTPTimestamp {
+            seconds: 3_900_000_000,
+            fraction: 0,
+        });
+
+        let broadcast = NTPPacket {
+            mode: Mode::Broadcast,
+            ..reply_to(&request)
+        };
+        assert!(check_reply(&request, &broadcast).is_err());
+
+        let kiss = NTPPacket {
+            stratum: 0,
+            reference_id: ReferenceId::code("RATE"),
+            ..reply_to(&request)
+        };
+        let err = check_reply(&request, &kiss).unwrap_err();
+        assert!(err.to_string().contains("RATE"), "{}", err);
+    }
+}
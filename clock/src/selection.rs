@@ -0,0 +1,368 @@
+use std::fmt;
+
+use crate::ntp::NTPResult;
+
+/// Clustering never drops below this many survivors.
+const MIN_SURVIVORS: usize = 3;
+
+/// Added to every server's dispersion, as `ntpd` does, so that servers on the same
+/// network aren't told apart by microseconds of noise.
+const MIN_DISPERSION_MS: f64 = 1.0;
+
+/// One server's measurements, as far as selection is concerned. All in milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub offset: f64,
+    /// How wrong `offset` can be: half the round trip to the reference clock, plus
+    /// every dispersion on the way. The server's true offset is within
+    /// `offset ± root_distance` if it's telling the truth.
+    pub root_distance: f64,
+    /// RMS of the differences between the samples' offsets and the best sample's.
+    pub jitter: f64,
+}
+
+impl Candidate {
+    /// Builds a candidate from a server's samples, the one with the shortest round
+    /// trip first.
+    pub fn from_samples(best: &NTPResult, samples: &[NTPResult]) -> Self {
+        let reply = &best.reply;
+        let precision = 2f64.powi(reply.precision as i32) * 1e3;
+
+        let jitter = if samples.len() > 1 {
+            let squares: f64 = samples
+                .iter()
+                .map(|s| (s.offset_ms() - best.offset_ms()).powi(2))
+                .sum();
+            (squares / (samples.len() - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        let root_delay = reply.root_delay.as_secs_f64() * 1e3;
+        let root_dispersion = reply.root_dispersion.as_secs_f64() * 1e3;
+
+        Candidate {
+            offset: best.offset_ms(),
+            root_distance: (best.delay_ms().max(0.0) + root_delay) / 2.0
+                + root_dispersion
+                + MIN_DISPERSION_MS
+                + precision
+                + jitter,
+            jitter,
+        }
+    }
+
+    fn low(&self) -> f64 {
+        self.offset - self.root_distance
+    }
+
+    fn high(&self) -> f64 {
+        self.offset + self.root_distance
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Used in the combined offset, with this share of the weight.
+    Accepted { weight: f64 },
+    /// Its offset lies outside the interval the majority agrees on.
+    Falseticker,
+    /// A truechimer, but too far from the others; dropped by clustering.
+    Outlier { selection_jitter: f64 },
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Accepted { weight } => write!(f, "accepted, weight {:.0}%", weight * 1e2),
+            Verdict::Falseticker => write!(f, "falseticker, outside the agreed interval"),
+            Verdict::Outlier { selection_jitter } => write!(
+                f,
+                "outlier, {:.3}ms from the other survivors",
+                selection_jitter
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Selection {
+    /// One per candidate, in the same order.
+    pub verdicts: Vec<Verdict>,
+    /// The interval in which most candidates' true offsets lie.
+    pub interval: (f64, f64),
+    /// The weighted mean of the accepted candidates' offsets.
+    pub offset: f64,
+}
+
+/// Picks the candidates to trust and combines their offsets, following the selection,
+/// clustering and combining algorithms of RFC 5905, section 11.2.
+///
+/// # Returns
+///
+/// `None` when no majority of the candidates agrees on the time, in which case none
+/// of them can be trusted.
+pub fn select(candidates: &[Candidate]) -> Option<Selection> {
+    let interval = intersection(candidates)?;
+    let (low, high) = interval;
+
+    let mut verdicts: Vec<Option<Verdict>> = candidates
+        .iter()
+        .map(|c| {
+            if c.offset < low || c.offset > high {
+                Some(Verdict::Falseticker)
+            } else {
+                None // decided by clustering
+            }
+        })
+        .collect();
+
+    cluster(candidates, &mut verdicts);
+    let offset = combine(candidates, &mut verdicts);
+
+    Some(Selection {
+        verdicts: verdicts
+            .into_iter()
+            .map(|v| v.expect("every candidate has a verdict"))
+            .collect(),
+        interval,
+        offset,
+    })
+}
+
+/// Marzullo's algorithm, as amended for NTP: finds the smallest interval that
+/// intersects the correctness intervals of all but `f` candidates, for the smallest
+/// `f`, and contains the offsets of all but `f` of them. `f` must stay below half.
+fn intersection(candidates: &[Candidate]) -> Option<(f64, f64)> {
+    let n = candidates.len();
+
+    // -1 opens an interval, +1 closes it, 0 is an offset in its middle
+    let mut edges: Vec<(f64, i32)> = candidates
+        .iter()
+        .flat_map(|c| [(c.low(), -1), (c.offset, 0), (c.high(), 1)])
+        .collect();
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut allow = 0;
+    while 2 * allow < n {
+        let majority = (n - allow) as i32;
+        let mut found = 0;
+
+        let mut low = None;
+        let mut chime = 0;
+        for &(edge, kind) in &edges {
+            chime -= kind;
+            if chime >= majority {
+                low = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        let mut high = None;
+        chime = 0;
+        for &(edge, kind) in edges.iter().rev() {
+            chime += kind;
+            if chime >= majority {
+                high = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        if let (Some(low), Some(high)) = (low, high) {
+            if found <= allow && low <= high {
+                return Some((low, high));
+            }
+        }
+        allow += 1;
+    }
+
+    None
+}
+
+/// Drops the survivor furthest from the rest, while that's further than the
+/// survivors' own jitter, keeping at least `MIN_SURVIVORS`.
+fn cluster(candidates: &[Candidate], verdicts: &mut [Option<Verdict>]) {
+    loop {
+        let survivors: Vec<usize> = (0..candidates.len())
+            .filter(|&i| verdicts[i].is_none())
+            .collect();
+        if survivors.len() <= MIN_SURVIVORS {
+            return;
+        }
+
+        // the RMS of each survivor's offset differences to the others
+        let selection_jitter = |i: usize| -> f64 {
+            let squares: f64 = survivors
+                .iter()
+                .map(|&j| (candidates[i].offset - candidates[j].offset).powi(2))
+                .sum();
+            (squares / (survivors.len() - 1) as f64).sqrt()
+        };
+
+        let (worst, worst_jitter) = survivors
+            .iter()
+            .map(|&i| (i, selection_jitter(i)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("there are survivors");
+        let min_jitter = survivors
+            .iter()
+            .map(|&i| candidates[i].jitter)
+            .fold(f64::INFINITY, f64::min);
+
+        // removing more wouldn't make the rest any more precise than they already are
+        if worst_jitter <= min_jitter {
+            return;
+        }
+        verdicts[worst] = Some(Verdict::Outlier {
+            selection_jitter: worst_jitter,
+        });
+    }
+}
+
+/// Averages the survivors' offsets, each weighted by the inverse of its root distance.
+fn combine(candidates: &[Candidate], verdicts: &mut [Option<Verdict>]) -> f64 {
+    let weight = |c: &Candidate| 1.0 / c.root_distance.max(f64::MIN_POSITIVE);
+
+    let survivors: Vec<usize> = (0..candidates.len())
+        .filter(|&i| verdicts[i].is_none())
+        .collect();
+    let total: f64 = survivors.iter().map(|&i| weight(&candidates[i])).sum();
+
+    let mut offset = 0.0;
+    for i in survivors {
+        let share = weight(&candidates[i]) / total;
+        offset += candidates[i].offset * share;
+        verdicts[i] = Some(Verdict::Accepted { weight: share });
+    }
+
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(offset: f64, root_distance: f64, jitter: f64) -> Candidate {
+        Candidate {
+            offset,
+            root_distance,
+            jitter,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn intersection_of_agreeing_candidates() {
+        let candidates = [
+            candidate(10.0, 5.0, 0.0),
+            candidate(12.0, 5.0, 0.0),
+            candidate(11.0, 5.0, 0.0),
+        ];
+        assert_eq!(intersection(&candidates), Some((7.0, 15.0)));
+    }
+
+    #[test]
+    fn falsetickers_are_rejected() {
+        let candidates = [
+            candidate(10.0, 5.0, 0.0),
+            candidate(12.0, 5.0, 0.0),
+            candidate(11.0, 5.0, 0.0),
+            candidate(100.0, 5.0, 0.0),
+        ];
+
+        let selection = select(&candidates).unwrap();
+        assert_eq!(selection.interval, (7.0, 15.0));
+        assert_eq!(selection.verdicts[3], Verdict::Falseticker);
+        for verdict in &selection.verdicts[..3] {
+            assert!(matches!(verdict, Verdict::Accepted { .. }), "{}", verdict);
+        }
+        assert_close(selection.offset, 11.0);
+    }
+
+    #[test]
+    fn no_majority_means_no_selection() {
+        let candidates = [candidate(0.0, 1.0, 0.0), candidate(100.0, 1.0, 0.0)];
+        assert_eq!(intersection(&candidates), None);
+        assert!(select(&candidates).is_none());
+    }
+
+    #[test]
+    fn clustering_drops_outliers_down_to_the_survivors_jitter() {
+        // all truechimers, but the last is far from the rest
+        let candidates = [
+            candidate(0.0, 50.0, 3.0),
+            candidate(1.0, 50.0, 3.0),
+            candidate(2.0, 50.0, 3.0),
+            candidate(3.0, 50.0, 3.0),
+            candidate(30.0, 50.0, 3.0),
+        ];
+
+        let selection = select(&candidates).unwrap();
+        assert!(
+            matches!(selection.verdicts[4], Verdict::Outlier { .. }),
+            "{}",
+            selection.verdicts[4]
+        );
+        // the other four are within their own jitter of each other, so they all stay
+        for verdict in &selection.verdicts[..4] {
+            assert!(matches!(verdict, Verdict::Accepted { .. }), "{}", verdict);
+        }
+        assert_close(selection.offset, 1.5);
+    }
+
+    #[test]
+    fn clustering_keeps_a_minimum_of_survivors() {
+        let candidates = [
+            candidate(0.0, 50.0, 0.0),
+            candidate(10.0, 50.0, 0.0),
+            candidate(20.0, 50.0, 0.0),
+        ];
+
+        let selection = select(&candidates).unwrap();
+        assert_eq!(
+            selection
+                .verdicts
+                .iter()
+                .filter(|v| matches!(v, Verdict::Accepted { .. }))
+                .count(),
+            MIN_SURVIVORS
+        );
+    }
+
+    #[test]
+    fn combining_weights_by_inverse_root_distance() {
+        let candidates = [
+            candidate(0.0, 1.0, 0.0),
+            candidate(2.0, 2.0, 0.0),
+            candidate(1.0, 1.0, 0.0),
+        ];
+
+        let selection = select(&candidates).unwrap();
+        let weights: Vec<f64> = selection
+            .verdicts
+            .iter()
+            .map(|v| match v {
+                Verdict::Accepted { weight } => *weight,
+                other => panic!("{}", other),
+            })
+            .collect();
+        for (weight, expected) in weights.into_iter().zip([0.4, 0.2, 0.4]) {
+            assert_close(weight, expected);
+        }
+        assert_close(selection.offset, 0.8);
+    }
+}
@@ -0,0 +1,273 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::ntp::{
+    ntp_roundtrip, LeapIndicator, Mode, NTPPacket, NTPShort, NTPTimestamp, ReferenceId,
+    NTP_MESSAGE_LENGTH,
+};
+use crate::servers::ServerAddress;
+
+const UPSTREAM_POLL: i8 = 6; // log2 seconds, i.e. every 64s
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(1);
+const PRECISION: i8 = -20; // about a microsecond, what `Utc::now` resolves on common platforms
+
+/// The stratum of the local clock when there's no upstream, as `ntpd` uses for
+/// its undisciplined local clock. Low enough to be a last resort for clients.
+pub const LOCAL_STRATUM: u8 = 10;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Stratum to announce while no upstream is configured.
+    pub stratum: u8,
+    /// The server whose time is served instead of the local clock's, if any.
+    pub upstream: Option<ServerAddress>,
+}
+
+/// What the server reports about its own time source, filled in from the upstream.
+#[derive(Debug, Clone, Copy)]
+struct Source {
+    /// How far the upstream is ahead of the local clock, added to every time served.
+    offset: ChronoDuration,
+    leap: LeapIndicator,
+    stratum: u8,
+    reference_id: ReferenceId,
+    reference_time: NTPTimestamp,
+    root_delay: NTPShort,
+    root_dispersion: NTPShort,
+}
+
+impl Source {
+    fn local(stratum: u8) -> Self {
+        Source {
+            offset: ChronoDuration::zero(),
+            leap: LeapIndicator::NoWarning,
+            stratum,
+            reference_id: ReferenceId([127, 127, 1, 0]), // ntpd's address for the local clock
+            reference_time: Utc::now().into(),
+            root_delay: NTPShort::default(),
+            root_dispersion: NTPShort::default(),
+        }
+    }
+
+    /// Until the upstream answers, clients are told not to trust us.
+    fn unsynchronized() -> Self {
+        Source {
+            offset: ChronoDuration::zero(),
+            leap: LeapIndicator::Unsynchronized,
+            stratum: 16,
+            reference_id: ReferenceId::code("INIT"),
+            reference_time: NTPTimestamp::default(),
+            root_delay: NTPShort::default(),
+            root_dispersion: NTPShort::default(),
+        }
+    }
+
+    /// The time, as served.
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset
+    }
+}
+
+/// Answers NTP client requests on `config.port` with the local system clock, forever.
+///
+/// With an upstream, the time served is the local clock corrected by the upstream's
+/// offset, measured every 64 seconds, and the stratum, reference ID and root
+/// delay/dispersion describe the upstream. The system clock itself is left alone:
+/// that's up to `clock check-ntp` or `clock daemon`.
+pub fn serve(config: &ServerConfig) -> Result<(), io::Error> {
+    // on most systems an IPv6 socket takes IPv4 requests as well
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, config.port))
+        .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)))?;
+
+    let source = match &config.upstream {
+        None => Arc::new(Mutex::new(Source::local(config.stratum))),
+        Some(upstream) => {
+            let source = Arc::new(Mutex::new(Source::unsynchronized()));
+            follow(upstream.clone(), Arc::clone(&source));
+            source
+        }
+    };
+
+    println!("serving NTP on {}", socket.local_addr()?);
+    answer(&socket, &source)
+}
+
+/// Replies to the requests arriving on `socket` with the time `source` describes, forever.
+fn answer(socket: &UdpSocket, source: &Mutex<Source>) -> Result<(), io::Error> {
+    let mut request = [0; NTP_MESSAGE_LENGTH];
+    loop {
+        let (len, client) = match socket.recv_from(&mut request) {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let received = Utc::now();
+
+        let request = match NTPPacket::decode(&request[..len]) {
+            Ok(packet) => packet,
+            Err(_) => continue, // not NTP, or truncated: nothing to reply to
+        };
+        let source = *source.lock().expect("source mutex poisoned");
+
+        if let Some(reply) = reply_to(&request, &source, (received + source.offset).into()) {
+            // a client that went away is no reason to stop serving the others
+            if let Err(err) = socket.send_to(&reply.encode(), client) {
+                eprintln!("unable to reply to {}: {}", client, err);
+            }
+        }
+    }
+}
+
+/// Builds the reply to `request`, or `None` if it isn't a request we answer.
+fn reply_to(request: &NTPPacket, source: &Source, receive_time: NTPTimestamp) -> Option<NTPPacket> {
+    if request.mode != Mode::Client || !(3..=4).contains(&request.version) {
+        return None;
+    }
+
+    let mut reply = NTPPacket::new(Mode::Server);
+    reply.version = request.version;
+    reply.leap = source.leap;
+    reply.stratum = source.stratum;
+    reply.poll = request.poll;
+    reply.precision = PRECISION;
+    reply.root_delay = source.root_delay;
+    reply.root_dispersion = source.root_dispersion;
+    reply.reference_id = source.reference_id;
+    reply.reference_time = source.reference_time;
+    reply.originate_time = request.transmit_time;
+    reply.receive_time = receive_time;
+    reply.transmit_time = source.now().into(); // as late as possible
+
+    Some(reply)
+}
+
+/// Polls the upstream in the background, keeping `source` up to date.
+fn follow(upstream: ServerAddress, source: Arc<Mutex<Source>>) {
+    thread::spawn(move || loop {
+        // resolved again every time, so the upstream may move
+        let polled = upstream
+            .resolve()
+            .and_then(|address| ntp_roundtrip(address, UPSTREAM_TIMEOUT));
+
+        match polled {
+            Ok(result) => {
+                let upstream = &result.reply;
+                let delay = result.delay() as f64 / 1e3;
+
+                let offset = ChronoDuration::nanoseconds((result.offset_ms() * 1e6) as i64);
+
+                // we serve the upstream's time, one hop further away from the reference clock
+                *source.lock().expect("source mutex poisoned") = Source {
+                    offset,
+                    leap: upstream.leap,
+                    stratum: upstream.stratum.saturating_add(1).min(16),
+                    reference_id: result.server.ip().into(),
+                    reference_time: (result.t4 + offset).into(),
+                    root_delay: NTPShort::from_secs_f64(
+                        upstream.root_delay.as_secs_f64() + delay.abs(),
+                    ),
+                    root_dispersion: NTPShort::from_secs_f64(
+                        upstream.root_dispersion.as_secs_f64() + delay.abs() / 2.0,
+                    ),
+                };
+            }
+            Err(err) => eprintln!("upstream {} unavailable: {}", upstream, err),
+        }
+
+        thread::sleep(Duration::from_secs(1 << UPSTREAM_POLL));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn request(version: u8, mode: Mode) -> NTPPacket {
+        NTPPacket {
+            version,
+            mode,
+            poll: 6,
+            transmit_time: NTPTimestamp {
+                seconds: 3_900_000_000,
+                fraction: 0x1234_5678,
+            },
+            ..NTPPacket::new(mode)
+        }
+    }
+
+    #[test]
+    fn replies_echo_the_request() {
+        let source = Source::local(LOCAL_STRATUM);
+        let receive_time: NTPTimestamp = Utc::now().into();
+
+        for version in [3, 4] {
+            let request = request(version, Mode::Client);
+            let reply = reply_to(&request, &source, receive_time).unwrap();
+
+            assert_eq!(reply.mode, Mode::Server);
+            assert_eq!(reply.version, version);
+            assert_eq!(reply.stratum, LOCAL_STRATUM);
+            assert_eq!(reply.poll, request.poll);
+            assert_eq!(reply.originate_time, request.transmit_time);
+            assert_eq!(reply.receive_time, receive_time);
+            assert!(DateTime::<Utc>::from(reply.transmit_time) >= receive_time.into());
+        }
+    }
+
+    #[test]
+    fn only_client_requests_of_known_versions_are_answered() {
+        let source = Source::local(LOCAL_STRATUM);
+        let receive_time: NTPTimestamp = Utc::now().into();
+
+        for version in [0, 1, 2, 5, 7] {
+            assert!(reply_to(&request(version, Mode::Client), &source, receive_time).is_none());
+        }
+        for mode in [
+            Mode::SymmetricActive,
+            Mode::Server,
+            Mode::Broadcast,
+            Mode::Control,
+        ] {
+            assert!(reply_to(&request(4, mode), &source, receive_time).is_none());
+        }
+    }
+
+    #[test]
+    fn the_upstreams_time_is_served() {
+        let source = Source {
+            offset: ChronoDuration::seconds(30),
+            ..Source::local(3)
+        };
+
+        let reply = reply_to(&request(4, Mode::Client), &source, Utc::now().into()).unwrap();
+        let ahead = DateTime::<Utc>::from(reply.transmit_time) - Utc::now();
+        assert!(ahead > ChronoDuration::seconds(29), "{}", ahead);
+    }
+
+    #[test]
+    fn clients_get_the_time_over_loopback() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address: SocketAddr = socket.local_addr().unwrap();
+        let source = Mutex::new(Source::local(LOCAL_STRATUM));
+        thread::spawn(move || answer(&socket, &source));
+
+        let result = ntp_roundtrip(address, Duration::from_secs(5)).unwrap();
+        assert_eq!(result.server, address);
+        assert_eq!(result.reply.mode, Mode::Server);
+        assert_eq!(result.reply.stratum, LOCAL_STRATUM);
+        assert_eq!(result.reply.reference_id, ReferenceId([127, 127, 1, 0]));
+
+        // the same clock at both ends
+        assert!(
+            result.offset_ms().abs() < 1000.0,
+            "{}ms",
+            result.offset_ms()
+        );
+    }
+}
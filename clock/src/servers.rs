@@ -0,0 +1,277 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::ntp::{ntp_roundtrip, NTPResult};
+
+pub const NTP_PORT: u16 = 123;
+
+/// Pause between two samples from the same server, so we don't get rate limited.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// An NTP server as given on the command line or in a servers file: `host`, `host:port`,
+/// an IPv6 address, or `[ipv6]:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for ServerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid server address {:?}", s);
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            // [ipv6] or [ipv6]:port
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        } else if s.parse::<Ipv6Addr>().is_ok() {
+            (s, None) // its colons aren't a port separator
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => NTP_PORT,
+        };
+
+        Ok(ServerAddress {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+
+        if self.port == NTP_PORT {
+            write!(f, "{}", host)
+        } else {
+            write!(f, "{}:{}", host, self.port)
+        }
+    }
+}
+
+impl ServerAddress {
+    /// Looks the host up, picking its first address.
+    pub fn resolve(&self) -> Result<SocketAddr, io::Error> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses", self.host),
+                )
+            })
+    }
+}
+
+/// Reads a servers file: one server per line, in any form `ServerAddress` accepts.
+/// Blank lines and everything after a `#` are ignored.
+pub fn read_servers_file(path: &Path) -> Result<Vec<ServerAddress>, io::Error> {
+    let contents = fs::read_to_string(path)?;
+
+    let mut servers = vec![];
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let server = line.parse().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), n + 1, err),
+            )
+        })?;
+        servers.push(server);
+    }
+
+    Ok(servers)
+}
+
+/// How `query_servers` queries each server.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryOptions {
+    pub samples: usize,
+    pub timeout: Duration,
+}
+
+/// The replies of one server. Holds an error only if none of the samples got a reply.
+#[derive(Debug)]
+pub struct ServerSamples {
+    pub server: ServerAddress,
+    pub samples: Result<Vec<NTPResult>, io::Error>,
+}
+
+impl ServerSamples {
+    /// The sample with the shortest round trip, which is the least disturbed by
+    /// network queueing, as in NTP's clock filter.
+    pub fn best(&self) -> Option<&NTPResult> {
+        self.samples.as_ref().ok()?.iter().min_by_key(|s| s.delay())
+    }
+}
+
+/// Queries every server at once, one thread each, and returns their samples in the
+/// order of `servers`.
+pub fn query_servers(servers: &[ServerAddress], options: QueryOptions) -> Vec<ServerSamples> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = servers
+            .iter()
+            .map(|server| scope.spawn(move || query_server(server, options)))
+            .collect();
+
+        servers
+            .iter()
+            .zip(handles)
+            .map(|(server, handle)| ServerSamples {
+                server: server.clone(),
+                samples: handle.join().expect("query thread panicked"),
+            })
+            .collect()
+    })
+}
+
+fn query_server(
+    server: &ServerAddress,
+    options: QueryOptions,
+) -> Result<Vec<NTPResult>, io::Error> {
+    let address = server.resolve()?;
+
+    let mut samples = Vec::with_capacity(options.samples);
+    let mut last_error = None;
+
+    for i in 0..options.samples {
+        if i > 0 {
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+        match ntp_roundtrip(address, options.timeout) {
+            Ok(sample) => samples.push(sample),
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    match last_error {
+        Some(err) if samples.is_empty() => Err(err),
+        _ => Ok(samples),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(host: &str, port: u16) -> ServerAddress {
+        ServerAddress {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn addresses_are_parsed() {
+        for (input, expected) in [
+            ("time.google.com", address("time.google.com", NTP_PORT)),
+            ("time.google.com:1123", address("time.google.com", 1123)),
+            ("192.0.2.1", address("192.0.2.1", NTP_PORT)),
+            ("192.0.2.1:123", address("192.0.2.1", 123)),
+            ("::1", address("::1", NTP_PORT)),
+            ("2001:db8::123", address("2001:db8::123", NTP_PORT)),
+            ("[::1]", address("::1", NTP_PORT)),
+            ("[::1]:123", address("::1", 123)),
+            ("[2001:db8::1]:1123", address("2001:db8::1", 1123)),
+        ] {
+            assert_eq!(input.parse(), Ok(expected), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        for input in [
+            "",
+            ":123",
+            "[]",
+            "[::1",
+            "[::1]x",
+            "[::1]:",
+            "host:abc",
+            "host:",
+            "host:65536",
+            "host:-1",
+        ] {
+            assert!(input.parse::<ServerAddress>().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn addresses_display_as_they_are_parsed() {
+        for input in [
+            "time.google.com",
+            "time.google.com:1123",
+            "192.0.2.1:1123",
+            "[::1]",
+            "[::1]:1123",
+        ] {
+            let parsed: ServerAddress = input.parse().unwrap();
+            assert_eq!(parsed.to_string(), input);
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+
+        // the default port and the brackets are only written when they're needed
+        assert_eq!("::1".parse::<ServerAddress>().unwrap().to_string(), "[::1]");
+        assert_eq!(address("host", NTP_PORT).to_string(), "host");
+    }
+
+    #[test]
+    fn servers_files_list_one_address_per_line() {
+        let path = std::env::temp_dir().join(format!("clock-servers-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# lab servers\n\ntime.lab:1123\n  [::1]:123  # loopback\n192.0.2.1\n",
+        )
+        .unwrap();
+        let servers = read_servers_file(&path);
+
+        fs::write(&path, "time.lab\nhost:abc\n").unwrap();
+        let invalid = read_servers_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            servers.unwrap(),
+            vec![
+                address("time.lab", 1123),
+                address("::1", 123),
+                address("192.0.2.1", NTP_PORT),
+            ]
+        );
+        let err = invalid.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .ends_with(":2: invalid server address \"host:abc\""));
+    }
+}